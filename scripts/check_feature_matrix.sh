@@ -0,0 +1,25 @@
+#!/bin/bash -e
+# check_* マクロの段階の組み合わせが、どれもビルドできることを確認する
+PROJ_ROOT="$(dirname ${BASH_SOURCE:-$0})/.."
+cd "${PROJ_ROOT}"
+
+cargo build --no-default-features
+cargo build --no-default-features --features check-fast,check-paging
+cargo build
+cargo build --features check-gfx
+
+# 無効にした検査が本当に消えていることを、アサーションのメッセージが
+# バイナリに残っているかどうかで確かめる
+CANARY="check_gfx: pixel out of range"
+EFI=target/x86_64-unknown-uefi/release/wasabi.efi
+cargo build --release
+if grep -qa "${CANARY}" "${EFI}"; then
+    echo "FAIL: check_gfx! is compiled in without the check-gfx feature"
+    exit 1
+fi
+cargo build --release --features check-gfx
+if ! grep -qa "${CANARY}" "${EFI}"; then
+    echo "FAIL: check_gfx! is missing with the check-gfx feature"
+    exit 1
+fi
+echo "PASS"
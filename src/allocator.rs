@@ -183,15 +183,22 @@ impl FirstFitAllocator {
             }
         }
     }
-    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) {
+    pub fn init_with_mmap(&self, memory_map: &MemoryMapHolder) -> Result<()> {
+        let mut added = false;
         for e in memory_map.iter() {
             if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
                 continue;
             }
-            self.add_free_from_descriptor(e);
+            added |= self.add_free_from_descriptor(e);
+        }
+        if added {
+            Ok(())
+        } else {
+            Err("No conventional memory for the heap")
         }
     }
-    fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) {
+    // 空き領域として追加できたらtrueを返す
+    fn add_free_from_descriptor(&self, desc: &EfiMemoryDescriptor) -> bool {
         let mut start_addr = desc.physical_start() as usize;
         let mut size = desc.number_of_pages() as usize * 4096;
         // Make sure the allocator does not include the address 0 as a free
@@ -201,7 +208,7 @@ impl FirstFitAllocator {
             size = size.saturating_sub(4096);
         }
         if size <= 4096 {
-            return;
+            return false;
         }
         let mut header = unsafe { Header::new_from_addr(start_addr) };
         header.next_header = None;
@@ -215,6 +222,7 @@ impl FirstFitAllocator {
         // It's okay not to be sorted the headers at this point
         // since all the regions written in memory maps are not contiguous
         // so that they can't be merged anyway
+        true
     }
 }
 
@@ -263,6 +271,115 @@ mod test {
         }
     }
 
+    // 再現可能な疑似乱数(xorshift64)
+    struct XorShift64(u64);
+    impl XorShift64 {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+    }
+
+    // 空きリストをたどって不変条件を確認し、(全ヘッダの大きさの合計, 確保済みのヘッダの数)を返す。
+    // ヘッダは32バイト境界にあり、自分自身より小さくなく、確保済みの領域は2の累乗+ヘッダの大きさで、
+    // リストは循環しない
+    fn free_list_stats(allocator: &FirstFitAllocator) -> Result<(usize, usize)> {
+        const MAX_HEADERS: usize = 1 << 20;
+        let first_header = allocator.first_header.borrow();
+        let mut header = first_header.as_deref();
+        let mut total = 0usize;
+        let mut allocated = 0;
+        let mut count = 0;
+        while let Some(h) = header {
+            if h as *const Header as usize % HEADER_SIZE != 0 {
+                return Err("free list: misaligned header");
+            }
+            if h.size < HEADER_SIZE {
+                return Err("free list: header smaller than itself");
+            }
+            if h.is_allocated() {
+                if !(h.size - HEADER_SIZE).is_power_of_two() {
+                    return Err("free list: allocated size is not a power of two");
+                }
+                allocated += 1;
+            }
+            total = total.checked_add(h.size).ok_or("free list: size overflow")?;
+            count += 1;
+            if count > MAX_HEADERS {
+                return Err("free list: too many headers (cycle?)");
+            }
+            header = h.next_header.as_deref();
+        }
+        Ok((total, allocated))
+    }
+
+    // ランダムな大きさとアラインメントでallocとdeallocを繰り返し、
+    // 生きている領域同士が重ならず、中身が壊れていないことを確認し続ける。
+    // 操作のたびに空きリストも検査する。分割しても解放しても全体の大きさは変わらず、
+    // 確保済みのヘッダは生きている領域の数だけ増える。
+    // 失敗したときはseedと何回目の操作かを表示するので、同じseedで再現できる
+    #[test_case]
+    fn malloc_fuzz() {
+        const SEED: u64 = 0x5eed_2024_0101_cafe;
+        const OPS: usize = 10000;
+        const SLOTS: usize = 64;
+        let mut rng = XorShift64(SEED);
+        let mut live: [Option<(*mut u8, Layout, u8)>; SLOTS] = [None; SLOTS];
+        // 他のテストが解放せずに残した領域があるので、開始時点からの差で比べる
+        let (total, allocated_before) = free_list_stats(&ALLOCATOR).unwrap();
+        let check_free_list = |op: usize, live_count: usize| match free_list_stats(&ALLOCATOR) {
+            Ok(stats) => assert_eq!(
+                stats,
+                (total, allocated_before + live_count),
+                "malloc_fuzz: free list accounting: seed={SEED:#x} op={op}"
+            ),
+            Err(e) => panic!("malloc_fuzz: {e}: seed={SEED:#x} op={op}"),
+        };
+        for op in 0..OPS {
+            let slot = rng.next() as usize % SLOTS;
+            if let Some((p, layout, tag)) = live[slot].take() {
+                for k in 0..layout.size() {
+                    assert!(
+                        unsafe { *p.add(k) } == tag,
+                        "malloc_fuzz: corrupted allocation: seed={SEED:#x} op={op}"
+                    );
+                }
+                unsafe { ALLOCATOR.dealloc(p, layout) };
+                check_free_list(op, live.iter().flatten().count());
+                continue;
+            }
+            let size = 1 + rng.next() as usize % 4096;
+            let align = 1 << (rng.next() % 13);
+            let layout = Layout::from_size_align(size, align).expect("Failed to create Layout");
+            let p = ALLOCATOR.alloc_with_options(layout);
+            assert!(!p.is_null(), "malloc_fuzz: out of memory: seed={SEED:#x} op={op}");
+            assert!(
+                p as usize % align == 0,
+                "malloc_fuzz: misaligned allocation: seed={SEED:#x} op={op}"
+            );
+            let range = p as usize..p as usize + size;
+            for (q, other, _) in live.iter().flatten() {
+                let other = *q as usize..*q as usize + other.size();
+                assert!(
+                    range.end <= other.start || other.end <= range.start,
+                    "malloc_fuzz: overlapping allocations: seed={SEED:#x} op={op}"
+                );
+            }
+            let tag = op as u8;
+            for k in 0..size {
+                unsafe { *p.add(k) = tag }
+            }
+            live[slot] = Some((p, layout, tag));
+            check_free_list(op, live.iter().flatten().count());
+        }
+        for (p, layout, _) in live.iter().flatten() {
+            unsafe { ALLOCATOR.dealloc(*p, *layout) };
+        }
+        check_free_list(OPS, 0);
+    }
+
     // 確保した領域が重複しない
     #[test_case]
     fn allocated_object_have_no_overlap() {
@@ -0,0 +1,192 @@
+use crate::graphics::Bitmap;
+use crate::result::Result;
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_MIN_SIZE: u32 = 40;
+const BI_RGB: u32 = 0;
+
+/// Header of an uncompressed 24/32-bit BMP, validated against the length of the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BmpInfo {
+    pub width: i64,
+    pub height: i64,
+    bytes_per_pixel: usize,
+    pixel_offset: usize,
+    stride: usize,
+    top_down: bool,
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or("BMP: header is truncated")
+}
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or("BMP: header is truncated")
+}
+
+impl BmpInfo {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if !data.starts_with(b"BM") {
+            return Err("BMP: bad magic");
+        }
+        let pixel_offset = read_u32(data, 10)? as usize;
+        if read_u32(data, FILE_HEADER_SIZE)? < INFO_HEADER_MIN_SIZE {
+            return Err("BMP: unsupported info header");
+        }
+        let width = read_u32(data, 18)? as i32 as i64;
+        let height = read_u32(data, 22)? as i32 as i64;
+        if read_u16(data, 26)? != 1 {
+            return Err("BMP: planes must be 1");
+        }
+        let bytes_per_pixel = match read_u16(data, 28)? {
+            24 => 3,
+            32 => 4,
+            _ => return Err("BMP: only 24 and 32 bits per pixel are supported"),
+        };
+        if read_u32(data, 30)? != BI_RGB {
+            return Err("BMP: compressed images are not supported");
+        }
+        if width <= 0 || height == 0 {
+            return Err("BMP: empty image");
+        }
+        // 高さが負の場合は上の行から並んでいる
+        let top_down = height < 0;
+        let height = height.abs();
+        // 各行は4バイト境界までパディングされる
+        let stride = (width as usize * bytes_per_pixel + 3) & !3;
+        let end = stride
+            .checked_mul(height as usize)
+            .and_then(|size| size.checked_add(pixel_offset))
+            .ok_or("BMP: image is too large")?;
+        if end > data.len() {
+            return Err("BMP: pixel data is truncated");
+        }
+        Ok(Self {
+            width,
+            height,
+            bytes_per_pixel,
+            pixel_offset,
+            stride,
+            top_down,
+        })
+    }
+    /// Pixel (x, y) as 0x00RRGGBB. (x, y) must be within width x height.
+    fn pixel(&self, data: &[u8], x: i64, y: i64) -> u32 {
+        let row = if self.top_down { y } else { self.height - 1 - y };
+        let i = self.pixel_offset + row as usize * self.stride + x as usize * self.bytes_per_pixel;
+        // BMPの画素はB, G, Rの順に並ぶ
+        u32::from_le_bytes([data[i], data[i + 1], data[i + 2], 0])
+    }
+}
+
+/// Draws an uncompressed 24/32-bit BMP (e.g. include_bytes!'d) with its top-left corner at
+/// (x, y). The parts outside of buf are clipped. Malformed data is an error and nothing
+/// is drawn in that case.
+pub fn draw_bmp<T: Bitmap + ?Sized>(buf: &mut T, x: i64, y: i64, data: &[u8]) -> Result<()> {
+    let info = BmpInfo::parse(data)?;
+    for iy in 0..info.height {
+        let Some(py) = y.checked_add(iy).filter(|py| buf.is_in_y_range(*py)) else {
+            continue;
+        };
+        for ix in 0..info.width {
+            let Some(px) = x.checked_add(ix).filter(|px| buf.is_in_x_range(*px)) else {
+                continue;
+            };
+            // SAFETY: (px, py) is checked above.
+            unsafe {
+                *buf.unchecked_pixel_at_mut(px, py) = info.pixel(data, ix, iy);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::graphics::BitmapBuffer;
+
+    // 幅3x高さ2の24bitのBMP。下の行から順に並び、各行は9バイト+パディング3バイト
+    const BMP_3X2: [u8; 54 + 24] = {
+        let mut d = [0u8; 54 + 24];
+        d[0] = b'B';
+        d[1] = b'M';
+        d[10] = 54;
+        d[14] = 40;
+        d[18] = 3;
+        d[22] = 2;
+        d[26] = 1;
+        d[28] = 24;
+        // 下の行: 赤, 緑, 青
+        let bottom = [0x00, 0x00, 0xff, 0x00, 0xff, 0x00, 0xff, 0x00, 0x00];
+        // 上の行: 白, 黒, 0x123456
+        let top = [0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x56, 0x34, 0x12];
+        let mut i = 0;
+        while i < 9 {
+            d[54 + i] = bottom[i];
+            d[54 + 12 + i] = top[i];
+            i += 1;
+        }
+        d
+    };
+
+    #[test_case]
+    fn draw_bmp_bottom_up_with_padding() {
+        let mut bmp = BitmapBuffer::new(4, 3);
+        draw_bmp(&mut bmp, 1, 1, &BMP_3X2).unwrap();
+        let row = |y| [1, 2, 3].map(|x| bmp.pixel_at(x, y).unwrap());
+        assert_eq!(row(1), [0xffffff, 0x000000, 0x123456]);
+        assert_eq!(row(2), [0xff0000, 0x00ff00, 0x0000ff]);
+        assert_eq!(bmp.pixel_at(0, 1), Some(0));
+        assert_eq!(bmp.pixel_at(1, 0), Some(0));
+        // はみ出した部分は切り取られる
+        let mut bmp = BitmapBuffer::new(2, 2);
+        draw_bmp(&mut bmp, -1, 1, &BMP_3X2).unwrap();
+        assert_eq!(bmp.pixel_at(0, 1), Some(0x000000));
+        assert_eq!(bmp.pixel_at(1, 1), Some(0x123456));
+        assert_eq!(bmp.pixel_at(0, 0), Some(0));
+        draw_bmp(&mut bmp, i64::MAX, i64::MIN, &BMP_3X2).unwrap();
+    }
+
+    #[test_case]
+    fn draw_bmp_top_down_32bit() {
+        let mut d = [0u8; 54 + 8];
+        d[..54].copy_from_slice(&BMP_3X2[..54]);
+        d[18] = 1;
+        d[22..26].copy_from_slice(&(-2i32).to_le_bytes());
+        d[28] = 32;
+        d[54..62].copy_from_slice(&[0x03, 0x02, 0x01, 0xff, 0x06, 0x05, 0x04, 0xff]);
+        let mut bmp = BitmapBuffer::new(1, 2);
+        draw_bmp(&mut bmp, 0, 0, &d).unwrap();
+        assert_eq!(bmp.pixel_at(0, 0), Some(0x010203));
+        assert_eq!(bmp.pixel_at(0, 1), Some(0x040506));
+    }
+
+    #[test_case]
+    fn draw_bmp_rejects_malformed() {
+        let mut bmp = BitmapBuffer::new(4, 4);
+        assert!(draw_bmp(&mut bmp, 0, 0, &BMP_3X2[..BMP_3X2.len() - 1]).is_err());
+        assert!(draw_bmp(&mut bmp, 0, 0, &BMP_3X2[..30]).is_err());
+        assert!(draw_bmp(&mut bmp, 0, 0, b"PNG").is_err());
+        let mut d = BMP_3X2;
+        d[28] = 8;
+        assert!(draw_bmp(&mut bmp, 0, 0, &d).is_err());
+        let mut d = BMP_3X2;
+        d[30] = 1;
+        assert!(draw_bmp(&mut bmp, 0, 0, &d).is_err());
+        let mut d = BMP_3X2;
+        d[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(draw_bmp(&mut bmp, 0, 0, &d).is_err());
+        let mut d = BMP_3X2;
+        d[18..22].copy_from_slice(&i32::MAX.to_le_bytes());
+        assert!(draw_bmp(&mut bmp, 0, 0, &d).is_err());
+        for y in 0..4 {
+            for x in 0..4 {
+                assert_eq!(bmp.pixel_at(x, y), Some(0));
+            }
+        }
+    }
+}
@@ -0,0 +1,39 @@
+// 用途ごとに段階を分けたアサーション。
+// debug_assert!と違ってビルドプロファイルではなくCargoのfeatureで有効/無効を切り替えるので、
+// releaseのテストイメージでもページングの検査だけ残す、サイズ優先のビルドでは全部外す、といったことができる。
+// 無効なときは条件式は型検査だけされて評価されず、最適化で消える。
+//
+//   check-fast:   安価で常に入れておきたい検査
+//   check-paging: ページテーブルやアドレスの不変条件
+//   check-gfx:    描画の座標やバッファ範囲の検査(ピクセル単位で呼ばれるので重いため、defaultには含めない)
+
+pub const FAST_ENABLED: bool = cfg!(feature = "check-fast");
+pub const PAGING_ENABLED: bool = cfg!(feature = "check-paging");
+pub const GFX_ENABLED: bool = cfg!(feature = "check-gfx");
+
+#[macro_export]
+macro_rules! check_fast {
+    ($($arg:tt)*) => {
+        if $crate::checks::FAST_ENABLED {
+            assert!($($arg)*);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! check_paging {
+    ($($arg:tt)*) => {
+        if $crate::checks::PAGING_ENABLED {
+            assert!($($arg)*);
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! check_gfx {
+    ($($arg:tt)*) => {
+        if $crate::checks::GFX_ENABLED {
+            assert!($($arg)*);
+        }
+    };
+}
@@ -0,0 +1,150 @@
+use crate::result::Result;
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+/// A bitmap font whose glyphs are indexed by code point.
+/// Each glyph is `height` rows of `(width + 7) / 8` bytes, MSB first.
+/// The glyph data is borrowed, so an `include_bytes!`'d font costs no copy.
+#[derive(Debug, Clone, Copy)]
+pub struct Font<'a> {
+    glyphs: &'a [u8],
+    glyph_count: usize,
+    bytes_per_glyph: usize,
+    width: i64,
+    height: i64,
+}
+impl<'a> Font<'a> {
+    /// Parses a PSF1 or PSF2 font. The header is read once; glyph() is a constant-time lookup.
+    /// PSF unicode tables are ignored: glyph i is used for code point i.
+    pub fn parse_psf(data: &'a [u8]) -> Result<Self> {
+        if data.starts_with(&PSF2_MAGIC) {
+            Self::parse_psf2(data)
+        } else if data.starts_with(&PSF1_MAGIC) {
+            Self::parse_psf1(data)
+        } else {
+            Err("Font: not a PSF font")
+        }
+    }
+    fn parse_psf1(data: &'a [u8]) -> Result<Self> {
+        let &[_, _, mode, charsize, ..] = data else {
+            return Err("Font: PSF1 header is truncated");
+        };
+        let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+        Self::new(&data[4..], glyph_count, charsize as usize, 8, charsize as i64)
+    }
+    fn parse_psf2(data: &'a [u8]) -> Result<Self> {
+        // magic, version, headersize, flags, length, charsize, height, width の順に並ぶ
+        let field = |i: usize| -> Result<u32> {
+            data.get(i * 4..i * 4 + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .ok_or("Font: PSF2 header is truncated")
+        };
+        let header_size = field(2)? as usize;
+        let glyph_count = field(4)? as usize;
+        let bytes_per_glyph = field(5)? as usize;
+        let height = field(6)? as i64;
+        let width = field(7)? as i64;
+        let glyphs = data.get(header_size..).ok_or("Font: PSF2 header is truncated")?;
+        Self::new(glyphs, glyph_count, bytes_per_glyph, width, height)
+    }
+    /// A font over raw glyph data laid out as described on the type.
+    pub fn new(
+        glyphs: &'a [u8],
+        glyph_count: usize,
+        bytes_per_glyph: usize,
+        width: i64,
+        height: i64,
+    ) -> Result<Self> {
+        if width <= 0 || height <= 0 {
+            return Err("Font: glyph size must be positive");
+        }
+        if bytes_per_glyph < ((width as usize + 7) / 8) * height as usize {
+            return Err("Font: glyph is smaller than width x height");
+        }
+        let total = glyph_count
+            .checked_mul(bytes_per_glyph)
+            .ok_or("Font: too many glyphs")?;
+        let glyphs = glyphs.get(..total).ok_or("Font: glyph data is truncated")?;
+        Ok(Self {
+            glyphs,
+            glyph_count,
+            bytes_per_glyph,
+            width,
+            height,
+        })
+    }
+    pub fn width(&self) -> i64 {
+        self.width
+    }
+    pub fn height(&self) -> i64 {
+        self.height
+    }
+    pub fn glyph(&self, c: char) -> Option<&'a [u8]> {
+        let i = c as usize;
+        if i >= self.glyph_count {
+            return None;
+        }
+        self.glyphs.get(i * self.bytes_per_glyph..(i + 1) * self.bytes_per_glyph)
+    }
+    /// Whether the pixel (x, y) of `glyph` is set. Out of range coordinates are not set.
+    pub fn is_set(&self, glyph: &[u8], x: i64, y: i64) -> bool {
+        if !(0..self.width).contains(&x) || !(0..self.height).contains(&y) {
+            return false;
+        }
+        let bytes_per_row = (self.width as usize + 7) / 8;
+        glyph
+            .get(y as usize * bytes_per_row + x as usize / 8)
+            .is_some_and(|b| b & (0x80 >> (x % 8)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // 'A'(0x41)までの66グリフを持つ、幅10x高さ3のPSF2フォント。'A'だけ点が打たれている
+    fn psf2_blob() -> [u8; 32 + 66 * 6] {
+        let mut blob = [0u8; 32 + 66 * 6];
+        let header: [u32; 8] = [0x864ab572, 0, 32, 0, 66, 6, 3, 10];
+        for (i, v) in header.iter().enumerate() {
+            blob[i * 4..i * 4 + 4].copy_from_slice(&v.to_le_bytes());
+        }
+        let a = 32 + 0x41 * 6;
+        blob[a..a + 6].copy_from_slice(&[0b1000_0000, 0b0100_0000, 0, 0, 0xff, 0xc0]);
+        blob
+    }
+
+    #[test_case]
+    fn parse_psf2() {
+        let blob = psf2_blob();
+        let font = Font::parse_psf(&blob).unwrap();
+        assert_eq!((font.width(), font.height()), (10, 3));
+        let a = font.glyph('A').unwrap();
+        assert!(font.is_set(a, 0, 0));
+        assert!(!font.is_set(a, 1, 0));
+        assert!(font.is_set(a, 9, 0));
+        assert!(!font.is_set(a, 0, 1));
+        assert!((0..10).all(|x| font.is_set(a, x, 2)));
+        assert!(!font.is_set(a, 10, 2));
+        assert!(font.glyph('B').is_none());
+        assert!(Font::parse_psf(&blob[..blob.len() - 1]).is_err());
+        assert!(Font::parse_psf(&blob[..20]).is_err());
+    }
+
+    #[test_case]
+    fn parse_psf1() {
+        let mut blob = [0u8; 4 + 256 * 16];
+        blob[..4].copy_from_slice(&[0x36, 0x04, 0, 16]);
+        blob[4 + 0x41 * 16] = 0x81;
+        let font = Font::parse_psf(&blob).unwrap();
+        assert_eq!((font.width(), font.height()), (8, 16));
+        let a = font.glyph('A').unwrap();
+        assert!(font.is_set(a, 0, 0) && font.is_set(a, 7, 0) && !font.is_set(a, 1, 0));
+        assert!(font.glyph('\u{100}').is_none());
+        blob[2] = PSF1_MODE_512;
+        assert!(Font::parse_psf(&blob).is_err());
+        assert!(Font::parse_psf(b"not a font").is_err());
+    }
+}
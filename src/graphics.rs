@@ -1,21 +1,193 @@
+extern crate alloc;
+
+use crate::font::Font;
 use crate::result::Result;
+use alloc::vec::Vec;
+use core::cmp::max;
 use core::cmp::min;
 
+/// A 24-bit color, stored as 0x00RRGGBB (red in bits 16..24, blue in bits 0..8).
+/// Every drawing function accepts `impl Into<Color>`, so plain u32 literals in the
+/// 0x00RRGGBB form keep working.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(u32);
+impl Color {
+    pub const BLACK: Color = Color::rgb(0x00, 0x00, 0x00);
+    pub const WHITE: Color = Color::rgb(0xff, 0xff, 0xff);
+    pub const RED: Color = Color::rgb(0xff, 0x00, 0x00);
+    pub const GREEN: Color = Color::rgb(0x00, 0xff, 0x00);
+    pub const BLUE: Color = Color::rgb(0x00, 0x00, 0xff);
+    pub const YELLOW: Color = Color::rgb(0xff, 0xff, 0x00);
+    pub const CYAN: Color = Color::rgb(0x00, 0xff, 0xff);
+    pub const MAGENTA: Color = Color::rgb(0xff, 0x00, 0xff);
+
+    pub const fn rgb(r: u8, g: u8, b: u8) -> Self {
+        Self((r as u32) << 16 | (g as u32) << 8 | b as u32)
+    }
+    /// 0x00RRGGBB. The top 8 bits are ignored.
+    pub const fn from_hex(v: u32) -> Self {
+        Self(v & 0xffffff)
+    }
+    pub const fn to_u32(self) -> u32 {
+        self.0
+    }
+    /// The complementary color (each channel becomes 255 - channel).
+    pub const fn invert(self) -> Self {
+        Self(!self.0 & 0xffffff)
+    }
+    pub const fn r(&self) -> u8 {
+        (self.0 >> 16) as u8
+    }
+    pub const fn g(&self) -> u8 {
+        (self.0 >> 8) as u8
+    }
+    pub const fn b(&self) -> u8 {
+        self.0 as u8
+    }
+    /// Linear interpolation from a (t = 0) to b (t = 255), per channel.
+    pub fn lerp(a: Color, b: Color, t: u8) -> Color {
+        Color(blend_pixel(a.0, b.0, t))
+    }
+    /// Pixel value in the framebuffer's layout.
+    /// GOPのPixelRedGreenBlueReserved8BitPerColorではメモリ上にR, G, Bの順に並ぶので、u32としては0x00BBGGRRになる
+    pub const fn to_native(&self, format: PixelFormat) -> u32 {
+        match format {
+            PixelFormat::Bgrx => self.0,
+            PixelFormat::Rgbx => Color::rgb(self.b(), self.g(), self.r()).0,
+        }
+    }
+}
+impl From<u32> for Color {
+    /// Takes 0x00RRGGBB. The top 8 bits are ignored.
+    fn from(v: u32) -> Self {
+        Self::from_hex(v)
+    }
+}
+impl From<Color> for u32 {
+    fn from(c: Color) -> u32 {
+        c.0
+    }
+}
+
+/// Byte order of a 32-bit framebuffer pixel in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Blue, Green, Red, Reserved (GOP PixelBlueGreenRedReserved8BitPerColor, QEMU/OVMF default)
+    Bgrx,
+    /// Red, Green, Blue, Reserved (GOP PixelRedGreenBlueReserved8BitPerColor)
+    Rgbx,
+}
+
+fn color_u32(color: impl Into<Color>) -> u32 {
+    let color: Color = color.into();
+    color.0
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Point {
+    pub x: i64,
+    pub y: i64,
+}
+impl Point {
+    pub const fn new(x: i64, y: i64) -> Self {
+        Self { x, y }
+    }
+}
+
+/// An axis-aligned rectangle covering x..x + w, y..y + h.
+/// A rectangle with w <= 0 or h <= 0 is empty.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Rect {
+    pub x: i64,
+    pub y: i64,
+    pub w: i64,
+    pub h: i64,
+}
+impl Rect {
+    pub const fn new(x: i64, y: i64, w: i64, h: i64) -> Self {
+        Self { x, y, w, h }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.w <= 0 || self.h <= 0
+    }
+    /// x + w (exclusive). Saturates instead of wrapping around.
+    pub fn right(&self) -> i64 {
+        self.x.saturating_add(max(self.w, 0))
+    }
+    /// y + h (exclusive). Saturates instead of wrapping around.
+    pub fn bottom(&self) -> i64 {
+        self.y.saturating_add(max(self.h, 0))
+    }
+    pub fn contains(&self, p: Point) -> bool {
+        (self.x..self.right()).contains(&p.x) && (self.y..self.bottom()).contains(&p.y)
+    }
+    /// The part covered by both rectangles, or None if they don't overlap.
+    /// Rectangles that only touch at an edge don't overlap.
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let r = self.clamp_to(other);
+        if r.is_empty() {
+            None
+        } else {
+            Some(r)
+        }
+    }
+    /// The part of self inside `other`. Unlike intersection() this always returns a
+    /// rectangle: if they don't overlap, it is empty and placed on the edge of `other`.
+    pub fn clamp_to(&self, other: &Rect) -> Rect {
+        let x0 = min(max(self.x, other.x), other.right());
+        let y0 = min(max(self.y, other.y), other.bottom());
+        let x1 = max(min(self.right(), other.right()), x0);
+        let y1 = max(min(self.bottom(), other.bottom()), y0);
+        Rect::new(x0, y0, x1.saturating_sub(x0), y1.saturating_sub(y0))
+    }
+}
+
+// 描画可能な範囲をRectとして返す
+fn clip_rect<T: Bitmap + ?Sized>(buf: &T) -> Rect {
+    let (x0, y0, x1, y1) = buf.clip_bounds();
+    Rect::new(x0, y0, x1 - x0, y1 - y0)
+}
+
 pub trait Bitmap {
     fn bytes_per_pixel(&self) -> i64;
     fn pixels_per_line(&self) -> i64;
     fn width(&self) -> i64;
     fn height(&self) -> i64;
+    fn buf(&self) -> *const u8;
     fn buf_mut(&mut self) -> *mut u8;
     /// # Safety
     /// 
     /// Returned pointer is valid as long as the given coordinates are valid which means that passing is_in_*_range tests. 
     /// 返されるポインタは、与えられた座標が有効である限り有効であり、is_in_*_rangeテストをパスすることを意味する。
     unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u32 {
+        crate::check_gfx!(
+            self.is_in_x_range(x) && self.is_in_y_range(y),
+            "check_gfx: pixel out of range"
+        );
         self.buf_mut().add(
             ((y * self.pixels_per_line() + x) * self.bytes_per_pixel()) as usize,
         ) as *mut u32
     }
+    /// # Safety
+    ///
+    /// Same as unchecked_pixel_at_mut.
+    unsafe fn unchecked_pixel_at(&self, x: i64, y: i64) -> *const u32 {
+        crate::check_gfx!(
+            self.is_in_x_range(x) && self.is_in_y_range(y),
+            "check_gfx: pixel out of range"
+        );
+        self.buf().add(
+            ((y * self.pixels_per_line() + x) * self.bytes_per_pixel()) as usize,
+        ) as *const u32
+    }
+    fn pixel_at(&self, x: i64, y: i64) -> Option<u32> {
+        if self.is_in_x_range(x) && self.is_in_y_range(y) {
+            // SAFETY: (x,y) is always validated by the checks above.
+            unsafe { Some(*self.unchecked_pixel_at(x, y)) }
+        } else {
+            None
+        }
+    }
     fn pixel_at_mut(&mut self, x: i64, y: i64) -> Option<&mut u32> {
         if self.is_in_x_range(x) && self.is_in_y_range(y) {
             // SAFETY: (x,y) is always validated by the checks above. 上記によりx,yは常に安全
@@ -24,18 +196,33 @@ pub trait Bitmap {
             None
         }
     }
+    /// The drawable area as (x_begin, y_begin, x_end, y_end), ends exclusive.
+    /// Usually the whole bitmap; a Clipped bitmap narrows it. Functions that clip
+    /// instead of failing clip against this, and is_in_*_range checks against it.
+    fn clip_bounds(&self) -> (i64, i64, i64, i64) {
+        (0, 0, min(self.width(), self.pixels_per_line()), self.height())
+    }
     fn is_in_x_range(&self, px: i64) -> bool {
-        0 <= px && px < min(self.width(), self.pixels_per_line())
+        let (x0, _, x1, _) = self.clip_bounds();
+        x0 <= px && px < x1
     }
     fn is_in_y_range(&self, py: i64) -> bool {
-        0 <= py && py < self.height()
+        let (_, y0, _, y1) = self.clip_bounds();
+        y0 <= py && py < y1
+    }
+    /// Whether (x + 1, y) is stored right after (x, y), so that a row can be accessed
+    /// through the pointer of its first pixel. The row-based fast paths (fill_rect,
+    /// transfer_rect, ...) need this and go pixel by pixel when it is false,
+    /// e.g. for a RotatedBitmap.
+    fn has_contiguous_rows(&self) -> bool {
+        true
     }
 }
 
 /// # Safety
 /// 
 /// (x,y) must be a valid point in the buf.
-unsafe fn unchecked_draw_point<T: Bitmap> (
+unsafe fn unchecked_draw_point<T: Bitmap + ?Sized> (
     buf: &mut T,
     color: u32,
     x: i64,
@@ -44,7 +231,7 @@ unsafe fn unchecked_draw_point<T: Bitmap> (
     *buf.unchecked_pixel_at_mut(x, y) = color;
 }
 
-fn draw_point<T: Bitmap>(
+fn draw_point<T: Bitmap + ?Sized>(
     buf: &mut T,
     color: u32,
     x: i64,
@@ -54,143 +241,3971 @@ fn draw_point<T: Bitmap>(
     Ok(())
 }
 
-pub fn fill_rect<T: Bitmap>(
-    buf: &mut T,
-    color: u32,
+// 矩形の四隅がすべてbufの範囲内であることを確認し、右下の座標を返す
+fn check_rect_range<T: Bitmap + ?Sized>(
+    buf: &T,
     px: i64,
     py: i64,
     w: i64,
     h: i64,
-) -> Result<()> {
+) -> Result<(i64, i64)> {
+    if w < 0 || h < 0 {
+        return Err("Out of Range");
+    }
+    // 巨大な値が渡されても折り返して範囲内に見えないように、オーバーフローはエラーにする
+    let x_last = px.checked_add(w - 1).ok_or("Out of Range")?;
+    let y_last = py.checked_add(h - 1).ok_or("Out of Range")?;
     if !buf.is_in_x_range(px)
         || !buf.is_in_y_range(py)
-        || !buf.is_in_x_range(px + w - 1)
-        || !buf.is_in_y_range(py + h - 1)
+        || !buf.is_in_x_range(x_last)
+        || !buf.is_in_y_range(y_last)
     {
         return Err("Out of Range");
     }
-    for y in py..py + h {
-        for x in px..px + w {
-            unsafe {
-                unchecked_draw_point(buf, color, x, y);
+    Ok((x_last, y_last))
+}
+
+pub fn fill_rect<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    let (x_last, y_last) = check_rect_range(buf, px, py, w, h)?;
+    if !buf.has_contiguous_rows() {
+        for y in py..=y_last {
+            for x in px..=x_last {
+                // SAFETY: (x, y) is validated by the checks above.
+                unsafe { unchecked_draw_point(buf, color, x, y) };
             }
         }
+        return Ok(());
+    }
+    // dyn Bitmapでも仮想呼び出しが1行につき1回で済むように、行の先頭ポインタだけを取得する
+    for y in py..=y_last {
+        // SAFETY: (px, y)..(px + w - 1, y) is validated by the checks above.
+        unsafe { fill_row(buf.unchecked_pixel_at_mut(px, y), w as usize, color) };
     }
     Ok(())
 }
 
-// 直線となる整数座標の点を求める
-fn calc_slope_point(da: i64, db: i64, ia: i64) -> Option<i64> {
-    if da < db {
-        None
-    } else if da == 0 {
-        Some(0)
-    } else if (0..=da).contains(&ia) {
-        Some((2 * db * ia + da) / da / 2)
-    } else {
-        None
+/// # Safety
+///
+/// row..row + len must be valid u32 pixels.
+// 2画素分をまとめてu64で書き込む。先頭が8バイト境界にないときと末尾の半端はu32で書く
+unsafe fn fill_row(row: *mut u32, len: usize, color: u32) {
+    let mut p = row;
+    let mut n = len;
+    if n > 0 && (p as usize) % 8 != 0 {
+        *p = color;
+        p = p.add(1);
+        n -= 1;
+    }
+    let pair = (color as u64) << 32 | color as u64;
+    let q = p as *mut u64;
+    for i in 0..n / 2 {
+        *q.add(i) = pair;
+    }
+    if n % 2 == 1 {
+        *p.add(n - 1) = color;
     }
 }
 
-fn draw_line<T: Bitmap>(
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GradientDirection {
+    /// From the top row to the bottom row.
+    Vertical,
+    /// From the left column to the right column.
+    Horizontal,
+}
+
+/// Fills the rectangle with a gradient: the first row (or column) is `start`, the last
+/// is `end` and each channel is interpolated linearly in between, with integer math only.
+/// A rectangle one pixel high (or wide) is just filled with `start`.
+#[allow(clippy::too_many_arguments)]
+pub fn fill_rect_gradient<T: Bitmap + ?Sized>(
     buf: &mut T,
-    color: u32,
-    x0: i64,
-    y0: i64,
-    x1: i64,
-    y1: i64,
+    start: impl Into<Color>,
+    end: impl Into<Color>,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+    direction: GradientDirection,
 ) -> Result<()> {
-    if !buf.is_in_x_range(x0)
-        || !buf.is_in_x_range(x1)
-        || !buf.is_in_y_range(y0)
-        || !buf.is_in_y_range(y1)
-    {
-        return Err("Out of Range");
-    }
-    let dx = (x1 - x0).abs();
-    let sx = (x1 - x0).signum();
-    let dy = (y1 - y0).abs();
-    let sy = (y1 - y0).signum();
-    if dx >= dy {
-        for (rx, ry) in (0..dx).flat_map(|rx| calc_slope_point(dx, dy, rx).map(|ry| (rx, ry))) {
-            draw_point(buf, color, x0 + rx * sx, y0 + ry * sy)?;
+    let start = color_u32(start);
+    let end = color_u32(end);
+    let (_, y_last) = check_rect_range(buf, px, py, w, h)?;
+    match direction {
+        GradientDirection::Vertical => {
+            for y in py..=y_last {
+                let color = gradient_color(start, end, y - py, h - 1);
+                fill_rect(buf, color, px, y, w, 1)?;
+            }
         }
-    } else {
-        for (rx, ry) in (0..dy).flat_map(|ry| calc_slope_point(dy, dx, ry).map(|rx| (rx, ry))) {
-            draw_point(buf, color, x0 + rx * sx, y0 + ry * sy)?;
-        }
-    }
-    Ok(())
-}
-
-fn lookup_font(c: char) -> Option<[[char; 8]; 16]> {
-    const FONT_SOURCE: &str = include_str!("./font.txt");
-    static mut FONT_CACHE: Option<[[[char; 8]; 16]; 256]> = None;
-    if let Ok(c) = u8::try_from(c) {
-        let font = unsafe {
-            FONT_CACHE.get_or_insert_with(|| {
-                let mut font = [[['*'; 8]; 16]; 256];
-                let mut fi = FONT_SOURCE.split('\n');
-                while let Some(line) = fi.next() {
-                    if let Some(line) = line.strip_prefix("0x") {
-                        if let Ok(idx) = u8::from_str_radix(line, 16) {
-                            let mut glyph = [['*'; 8]; 16];
-                            for (y, line) in fi.clone().take(16).enumerate() {
-                                for (x, c) in line.chars().enumerate() {
-                                    if let Some(e) = glyph[y].get_mut(x) {
-                                        *e = c;
-                                    }
-                                }
-                            }
-                            font[idx as usize] = glyph;
+        GradientDirection::Horizontal => {
+            // 列ごとの色を一度だけ求めておき、各行にはそれを写すだけにする
+            let colors: Vec<u32> = (0..w)
+                .map(|x| gradient_color(start, end, x, w - 1))
+                .collect();
+            let contiguous = buf.has_contiguous_rows();
+            for y in py..=y_last {
+                // SAFETY: (px, y)..(px + w - 1, y) is validated by check_rect_range above.
+                unsafe {
+                    if contiguous {
+                        let row = buf.unchecked_pixel_at_mut(px, y);
+                        core::ptr::copy_nonoverlapping(colors.as_ptr(), row, colors.len());
+                    } else {
+                        for (x, color) in (px..).zip(&colors) {
+                            unchecked_draw_point(buf, *color, x, y);
                         }
                     }
                 }
-                font
-            })
-        };
-        Some(font[c as usize])
-    } else {
-        None
+            }
+        }
     }
+    Ok(())
 }
 
-pub fn draw_font_fg<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32, c: char) {
-    if let Some(font) = lookup_font(c) {
-        for (dy, row) in font.iter().enumerate(){
-            for (dx, pixel) in row.iter().enumerate() {
-                let color = match pixel {
-                    '*' => color,
-                    _ => continue,
-                };
-                let _ = draw_point(buf, color, x + dx as i64, y + dy as i64);
+// startからendまでをsteps等分したうちのi番目の色
+fn gradient_color(start: u32, end: u32, i: i64, steps: i64) -> u32 {
+    if steps == 0 {
+        return start;
+    }
+    let mut color = 0;
+    for shift in [0, 8, 16] {
+        let s = ((start >> shift) & 0xff) as i64;
+        let e = ((end >> shift) & 0xff) as i64;
+        // start + (end - start) * i / steps を四捨五入で求める
+        let c = (s * (steps - i) + e * i + steps / 2) / steps;
+        color |= (c as u32) << shift;
+    }
+    color
+}
+
+/// Same as fill_rect, but only the part of the rectangle inside buf is drawn.
+/// Rectangles partially or entirely off screen are not an error.
+pub fn fill_rect_clipped<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    // 描画可能な範囲との共通部分だけを塗る
+    let Some(r) = Rect::new(px, py, w, h).intersection(&clip_rect(buf)) else {
+        return Ok(());
+    };
+    fill_rect(buf, color, r.x, r.y, r.w, r.h)
+}
+
+/// Same as fill_rect, with the rectangle as a Rect.
+pub fn fill_rect_r<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    rect: Rect,
+) -> Result<()> {
+    fill_rect(buf, color, rect.x, rect.y, rect.w, rect.h)
+}
+
+/// Blends `src` over `dst` with opacity `alpha` (255: src, 0: dst), per 0x00RRGGBB channel.
+pub fn blend_pixel(dst: u32, src: u32, alpha: u8) -> u32 {
+    let a = alpha as u32;
+    let mut result = 0;
+    for shift in [0, 8, 16] {
+        let s = (src >> shift) & 0xff;
+        let d = (dst >> shift) & 0xff;
+        // +127で切り捨てではなく四捨五入にする
+        result |= ((s * a + d * (255 - a) + 127) / 255) << shift;
+    }
+    result
+}
+
+/// draw_point with translucency: the pixel becomes blend_pixel(existing, color, alpha).
+pub fn draw_point_blended<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    alpha: u8,
+    x: i64,
+    y: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    let p = buf.pixel_at_mut(x, y).ok_or("Out of Range")?;
+    *p = blend_pixel(*p, color, alpha);
+    Ok(())
+}
+
+/// fill_rect with translucency: each pixel becomes blend_pixel(existing, color, alpha).
+pub fn fill_rect_blended<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    alpha: u8,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    match alpha {
+        255 => return fill_rect(buf, color, px, py, w, h),
+        0 => return check_rect_range(buf, px, py, w, h).map(|_| ()),
+        _ => {}
+    }
+    let (x_last, y_last) = check_rect_range(buf, px, py, w, h)?;
+    for y in py..=y_last {
+        for x in px..=x_last {
+            // SAFETY: (x, y) is validated by check_rect_range above.
+            unsafe {
+                let dst = *buf.unchecked_pixel_at(x, y);
+                *buf.unchecked_pixel_at_mut(x, y) = blend_pixel(dst, color, alpha);
             }
         }
     }
+    Ok(())
 }
 
-pub fn draw_str_fg<T: Bitmap>(buf: &mut T, x: i64, y: i64, color: u32, s: &str) {
-    for (i, c) in s.chars().enumerate() {
-        draw_font_fg(buf, x + i as i64 * 8, y, color, c);
+// コピー元とコピー先の両方の範囲に収まるように、1軸分のコピー範囲を切り詰める。
+// 切り詰めた後の(コピー元の開始位置, コピー先の開始位置, 長さ)を返す
+fn clip_copy_axis(
+    src_pos: i64,
+    dst_pos: i64,
+    len: i64,
+    src_size: i64,
+    dst_size: i64,
+) -> Option<(i64, i64, i64)> {
+    let skip = max(0, max(src_pos.saturating_neg(), dst_pos.saturating_neg()));
+    if skip >= len {
+        return None;
     }
+    let src_pos = src_pos.saturating_add(skip);
+    let dst_pos = dst_pos.saturating_add(skip);
+    let len = min(
+        len - skip,
+        min(src_size.saturating_sub(src_pos), dst_size.saturating_sub(dst_pos)),
+    );
+    if len <= 0 {
+        return None;
+    }
+    Some((src_pos, dst_pos, len))
 }
 
-pub fn draw_test_pattern<T: Bitmap>(buf: &mut T) {
-    let w = 128;
-    let left = buf.width() - w - 1;
-    let colors = [0x000000, 0xff0000, 0x00ff00, 0x0000ff];
-    let h = 64;
-    for (i, c) in colors.iter().enumerate() {
-        let y = i as i64 * h;
-        fill_rect(buf, *c, left, y, h, h).expect("fill_rect failed");
-        fill_rect(buf, !*c, left + h, y, h, h).expect("fill_rect failed");
+/// Copies the w x h region at (sx, sy) of src to (dx, dy) of dst, one row at a time.
+/// The region is clipped against both bitmaps; nothing outside of them is touched.
+/// src and dst are always different bitmaps (the borrow checker guarantees it),
+/// so the rows never overlap.
+#[allow(clippy::too_many_arguments)]
+pub fn transfer_rect<D: Bitmap + ?Sized, S: Bitmap + ?Sized>(
+    dst: &mut D,
+    src: &S,
+    sx: i64,
+    sy: i64,
+    dx: i64,
+    dy: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    if w < 0 || h < 0 {
+        return Err("Out of Range");
     }
-    let points = [(0, 0), (0, w), (w, 0), (w, w)];
-    for (x0, y0) in points.iter() {
-        for (x1, y1) in points.iter() {
-            let _ = draw_line(buf, 0xffffff, left + *x0, *y0, left + *x1, *y1);
+    // 描画可能な範囲の左上を原点とした座標で切り詰めてから元に戻す
+    let (sx0, sy0, sx1, sy1) = src.clip_bounds();
+    let (dx0, dy0, dx1, dy1) = dst.clip_bounds();
+    let Some((sx, dx, w)) = clip_copy_axis(
+        sx.saturating_sub(sx0),
+        dx.saturating_sub(dx0),
+        w,
+        sx1 - sx0,
+        dx1 - dx0,
+    ) else {
+        return Ok(());
+    };
+    let Some((sy, dy, h)) = clip_copy_axis(
+        sy.saturating_sub(sy0),
+        dy.saturating_sub(dy0),
+        h,
+        sy1 - sy0,
+        dy1 - dy0,
+    ) else {
+        return Ok(());
+    };
+    let (sx, dx, sy, dy) = (sx + sx0, dx + dx0, sy + sy0, dy + dy0);
+    let contiguous = src.has_contiguous_rows() && dst.has_contiguous_rows();
+    for y in 0..h {
+        // SAFETY: both rows are within the bitmaps after clipping above.
+        unsafe {
+            if contiguous {
+                core::ptr::copy_nonoverlapping(
+                    src.unchecked_pixel_at(sx, sy + y),
+                    dst.unchecked_pixel_at_mut(dx, dy + y),
+                    w as usize,
+                );
+            } else {
+                for x in 0..w {
+                    *dst.unchecked_pixel_at_mut(dx + x, dy + y) =
+                        *src.unchecked_pixel_at(sx + x, sy + y);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Copies the w x h region at (src_x, src_y) of src to (dst_x, dst_y) of dst.
+/// Same as transfer_rect with the destination coordinates first; parts outside of
+/// either bitmap are clipped and zero-sized copies are no-ops.
+#[allow(clippy::too_many_arguments)]
+pub fn blit<Src: Bitmap + ?Sized, Dst: Bitmap + ?Sized>(
+    dst: &mut Dst,
+    dst_x: i64,
+    dst_y: i64,
+    src: &Src,
+    src_x: i64,
+    src_y: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    transfer_rect(dst, src, src_x, src_y, dst_x, dst_y, w, h)
+}
+
+/// Clockwise rotation of a panel relative to the image drawn for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rotation {
+    Rotate0,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+impl Rotation {
+    /// Size of the image after rotating a w x h image.
+    pub fn rotated_size(&self, w: i64, h: i64) -> (i64, i64) {
+        match self {
+            Rotation::Rotate0 | Rotation::Rotate180 => (w, h),
+            Rotation::Rotate90 | Rotation::Rotate270 => (h, w),
+        }
+    }
+    /// The rotation that undoes this one.
+    pub fn inverse(&self) -> Rotation {
+        match self {
+            Rotation::Rotate90 => Rotation::Rotate270,
+            Rotation::Rotate270 => Rotation::Rotate90,
+            r => *r,
+        }
+    }
+    // w x hの画像上の(x, y)が、回転後の画像上でどこに来るか
+    fn map(&self, x: i64, y: i64, w: i64, h: i64) -> (i64, i64) {
+        match self {
+            Rotation::Rotate0 => (x, y),
+            Rotation::Rotate90 => (h - 1 - y, x),
+            Rotation::Rotate180 => (w - 1 - x, h - 1 - y),
+            Rotation::Rotate270 => (y, w - 1 - x),
+        }
+    }
+}
+
+/// Copies the whole src into dst rotated by `rotation`, in one pass.
+/// Draw everything into an unrotated off-screen bitmap with the panel's logical size
+/// (Rotation::rotated_size of the VRAM size) and rotate only here, so that the
+/// row-based fast paths of the drawing functions keep working.
+/// (RotatedBitmap draws on dst directly instead, with the same mapping.)
+/// Pixels that fall outside of dst are clipped.
+pub fn transfer_rotated<D: Bitmap + ?Sized, S: Bitmap + ?Sized>(
+    dst: &mut D,
+    src: &S,
+    rotation: Rotation,
+) -> Result<()> {
+    if rotation == Rotation::Rotate0 {
+        return transfer_rect(dst, src, 0, 0, 0, 0, src.width(), src.height());
+    }
+    let w = min(src.width(), src.pixels_per_line());
+    let h = src.height();
+    let (sx0, sy0, sx1, sy1) = src.clip_bounds();
+    if sx0 >= sx1 {
+        return Ok(());
+    }
+    let contiguous = src.has_contiguous_rows();
+    for y in sy0..sy1 {
+        // SAFETY: (sx0, y)..(sx1 - 1, y) is within src.
+        let row = contiguous.then(|| unsafe { src.unchecked_pixel_at(sx0, y).sub(sx0 as usize) });
+        for x in sx0..sx1 {
+            let (rx, ry) = rotation.map(x, y, w, h);
+            if dst.is_in_x_range(rx) && dst.is_in_y_range(ry) {
+                // SAFETY: (rx, ry) is checked above, and (x, y) is within src.
+                unsafe {
+                    let p = match row {
+                        Some(row) => row.add(x as usize),
+                        None => src.unchecked_pixel_at(x, y),
+                    };
+                    *dst.unchecked_pixel_at_mut(rx, ry) = *p;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The inner bitmap (e.g. the VRAM of a panel mounted sideways) seen rotated back by
+/// `rotation`: (x, y) here is where the image is upright, and it is mapped to the inner
+/// bitmap like transfer_rotated() does. width() and height() are the upright size.
+/// Except for Rotate0 its rows are not contiguous in memory, so the drawing functions
+/// go pixel by pixel on it; compose large frames in a BackBuffer::new_like() of it and
+/// present() them.
+pub struct RotatedBitmap<T: Bitmap> {
+    inner: T,
+    rotation: Rotation,
+}
+impl<T: Bitmap> RotatedBitmap<T> {
+    pub fn new(inner: T, rotation: Rotation) -> Self {
+        Self { inner, rotation }
+    }
+    pub fn rotation(&self) -> Rotation {
+        self.rotation
+    }
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+    // 回転前(内側のビットマップ)の大きさ
+    fn inner_size(&self) -> (i64, i64) {
+        let inner = &self.inner;
+        (min(inner.width(), inner.pixels_per_line()), inner.height())
+    }
+    // この上の(x, y)に対応する内側のビットマップ上の座標
+    fn to_inner(&self, x: i64, y: i64) -> (i64, i64) {
+        self.rotation.map(x, y, self.width(), self.height())
+    }
+}
+impl<T: Bitmap> Bitmap for RotatedBitmap<T> {
+    fn bytes_per_pixel(&self) -> i64 {
+        self.inner.bytes_per_pixel()
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.width()
+    }
+    fn width(&self) -> i64 {
+        let (w, h) = self.inner_size();
+        self.rotation.rotated_size(w, h).0
+    }
+    fn height(&self) -> i64 {
+        let (w, h) = self.inner_size();
+        self.rotation.rotated_size(w, h).1
+    }
+    fn buf(&self) -> *const u8 {
+        self.inner.buf()
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.inner.buf_mut()
+    }
+    unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u32 {
+        crate::check_gfx!(
+            self.is_in_x_range(x) && self.is_in_y_range(y),
+            "check_gfx: pixel out of range"
+        );
+        let (x, y) = self.to_inner(x, y);
+        self.inner.unchecked_pixel_at_mut(x, y)
+    }
+    unsafe fn unchecked_pixel_at(&self, x: i64, y: i64) -> *const u32 {
+        crate::check_gfx!(
+            self.is_in_x_range(x) && self.is_in_y_range(y),
+            "check_gfx: pixel out of range"
+        );
+        let (x, y) = self.to_inner(x, y);
+        self.inner.unchecked_pixel_at(x, y)
+    }
+    // 内側の描画可能な範囲の対角を逆に回転させて、こちらの座標での範囲にする
+    fn clip_bounds(&self) -> (i64, i64, i64, i64) {
+        let (x0, y0, x1, y1) = self.inner.clip_bounds();
+        if x0 >= x1 || y0 >= y1 {
+            return (0, 0, 0, 0);
+        }
+        let (w, h) = self.inner_size();
+        let inverse = self.rotation.inverse();
+        let (ax, ay) = inverse.map(x0, y0, w, h);
+        let (bx, by) = inverse.map(x1 - 1, y1 - 1, w, h);
+        (min(ax, bx), min(ay, by), max(ax, bx) + 1, max(ay, by) + 1)
+    }
+    fn has_contiguous_rows(&self) -> bool {
+        self.rotation == Rotation::Rotate0 && self.inner.has_contiguous_rows()
+    }
+}
+
+/// Mirrors the rectangle left-right in place. The rectangle is validated like fill_rect.
+pub fn flip_region_h<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    let (x_last, y_last) = check_rect_range(buf, px, py, w, h)?;
+    for y in py..=y_last {
+        // 幅が奇数の場合、中央の列はそのまま残る
+        for i in 0..w / 2 {
+            // SAFETY: both points are validated by check_rect_range above.
+            unsafe {
+                core::ptr::swap(
+                    buf.unchecked_pixel_at_mut(px + i, y),
+                    buf.unchecked_pixel_at_mut(x_last - i, y),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Mirrors the rectangle upside down in place. The rectangle is validated like fill_rect.
+pub fn flip_region_v<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    let (x_last, y_last) = check_rect_range(buf, px, py, w, h)?;
+    let contiguous = buf.has_contiguous_rows();
+    for i in 0..h / 2 {
+        // SAFETY: both rows are validated by check_rect_range above and never overlap
+        // since py + i < y_last - i.
+        unsafe {
+            if contiguous {
+                core::ptr::swap_nonoverlapping(
+                    buf.unchecked_pixel_at_mut(px, py + i),
+                    buf.unchecked_pixel_at_mut(px, y_last - i),
+                    w as usize,
+                );
+            } else {
+                for x in px..=x_last {
+                    core::ptr::swap(
+                        buf.unchecked_pixel_at_mut(x, py + i),
+                        buf.unchecked_pixel_at_mut(x, y_last - i),
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves the contents of the rectangle up by `dy` pixels (down if `dy` is negative) and
+/// fills the vacated rows with `fill_color`. The rectangle is validated like fill_rect.
+/// If |dy| >= h, the whole rectangle is just filled.
+pub fn scroll_area<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+    dy: i64,
+    fill_color: impl Into<Color>,
+) -> Result<()> {
+    let color = color_u32(fill_color);
+    check_rect_range(buf, px, py, w, h)?;
+    if dy == 0 {
+        return Ok(());
+    }
+    if dy.unsigned_abs() >= h as u64 {
+        return fill_rect(buf, color, px, py, w, h);
+    }
+    let shift = dy.abs();
+    let rows = h - shift;
+    // 上にずらすときは上の行から、下にずらすときは下の行から写せば、
+    // まだ写していない行を上書きしない
+    let (src_y, dst_y, fill_y) = if dy > 0 {
+        (py + shift, py, py + rows)
+    } else {
+        (py, py + shift, py)
+    };
+    let contiguous = buf.has_contiguous_rows();
+    for i in 0..rows {
+        let i = if dy > 0 { i } else { rows - 1 - i };
+        // SAFETY: both rows are inside the rectangle validated by check_rect_range above.
+        unsafe {
+            if contiguous {
+                let src = buf.unchecked_pixel_at(px, src_y + i);
+                let dst = buf.unchecked_pixel_at_mut(px, dst_y + i);
+                core::ptr::copy(src, dst, w as usize);
+            } else {
+                for x in px..px + w {
+                    let src = *buf.unchecked_pixel_at(x, src_y + i);
+                    *buf.unchecked_pixel_at_mut(x, dst_y + i) = src;
+                }
+            }
+        }
+    }
+    fill_rect(buf, color, px, fill_y, w, shift)
+}
+
+/// Moves the whole drawable area of buf up by `rows` pixel rows and fills the rows
+/// exposed at the bottom with `fill`. rows >= height just fills everything,
+/// and rows == 0 does nothing.
+pub fn scroll_up<T: Bitmap + ?Sized>(buf: &mut T, rows: i64, fill: impl Into<Color>) -> Result<()> {
+    if rows < 0 {
+        return Err("rows must not be negative");
+    }
+    let r = clip_rect(buf);
+    if rows == 0 || r.is_empty() {
+        return Ok(());
+    }
+    scroll_area(buf, r.x, r.y, r.w, r.h, rows, fill)
+}
+
+/// Draws a line from (x0, y0) to (x1, y1) with Bresenham's algorithm, including both
+/// endpoints. Swapping the endpoints draws exactly the same pixels.
+/// The endpoints may be anywhere: only the part of the line inside buf is drawn (the same
+/// pixels a larger bitmap would get there), and a line entirely outside buf draws nothing.
+pub fn draw_line<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    let line = LineSteps::new(x0, y0, x1, y1);
+    let Some((k0, k1)) = line.clip(buf.clip_bounds()) else {
+        return Ok(());
+    };
+    // SAFETY: clip() only returns steps whose points are inside the clip bounds.
+    line.for_each(k0, k1, |x, y| unsafe {
+        unchecked_draw_point(buf, color, x, y)
+    });
+    Ok(())
+}
+
+// Bresenham法で描く線を、長い方の軸に沿ったk = 0..=major番目の点の列として扱う
+// k番目の点の短い方の軸の変化量はfloor((2 * k * minor + major - 1) / (2 * major))なので、
+// 範囲外の部分を飛ばして途中から描き始めても、全体を描いた場合と同じ点が選ばれる
+// 端点が極端な値でも桁あふれしないように、i128/u128で計算する
+struct LineSteps {
+    x_major: bool,
+    start_major: i128,
+    start_minor: i128,
+    minor_sign: i128,
+    major: u128,
+    minor: u128,
+}
+impl LineSteps {
+    fn new(x0: i64, y0: i64, x1: i64, y1: i64) -> Self {
+        let (x0, y0, x1, y1) = (x0 as i128, y0 as i128, x1 as i128, y1 as i128);
+        let dx = (x1 - x0).abs();
+        let dy = (y1 - y0).abs();
+        // A→BとB→Aで同じ点が選ばれるように、長い方の軸が増える向きに揃える
+        let ((x0, y0), (x1, y1)) = if (dx >= dy && x0 > x1) || (dx < dy && y0 > y1) {
+            ((x1, y1), (x0, y0))
+        } else {
+            ((x0, y0), (x1, y1))
+        };
+        if dx >= dy {
+            Self {
+                x_major: true,
+                start_major: x0,
+                start_minor: y0,
+                minor_sign: (y1 - y0).signum(),
+                major: dx as u128,
+                minor: dy as u128,
+            }
+        } else {
+            Self {
+                x_major: false,
+                start_major: y0,
+                start_minor: x0,
+                minor_sign: (x1 - x0).signum(),
+                major: dy as u128,
+                minor: dx as u128,
+            }
+        }
+    }
+    fn minor_offset(&self, k: u128) -> u128 {
+        if self.major == 0 {
+            return 0;
+        }
+        let p = k * self.minor;
+        p / self.major + (2 * (p % self.major) + self.major - 1) / (2 * self.major)
+    }
+    fn point(&self, k: u128, m: u128) -> (i64, i64) {
+        let major = self.start_major + k as i128;
+        let minor = self.start_minor + self.minor_sign * m as i128;
+        if self.x_major {
+            (major as i64, minor as i64)
+        } else {
+            (minor as i64, major as i64)
+        }
+    }
+    /// Range of steps whose points are inside `(x0, y0, x1, y1)` (exclusive ends), if any.
+    fn clip(&self, (cx0, cy0, cx1, cy1): (i64, i64, i64, i64)) -> Option<(u128, u128)> {
+        let x_range = (cx0 as i128, cx1 as i128 - 1);
+        let y_range = (cy0 as i128, cy1 as i128 - 1);
+        let (major_range, minor_range) = if self.x_major {
+            (x_range, y_range)
+        } else {
+            (y_range, x_range)
+        };
+        // 長い方の軸は1歩ごとに1増えるので、範囲内のkはそのまま求まる
+        let k_first = max(major_range.0 - self.start_major, 0);
+        let k_last = min(major_range.1 - self.start_major, self.major as i128);
+        if k_first > k_last {
+            return None;
+        }
+        // 短い方の軸の変化量はkについて単調なので、範囲の両端は二分探索で求める
+        let (lo, hi) = minor_range;
+        let (m_lo, m_hi) = if self.minor_sign >= 0 {
+            (lo - self.start_minor, hi - self.start_minor)
+        } else {
+            (self.start_minor - hi, self.start_minor - lo)
+        };
+        if m_hi < 0 || m_lo > m_hi {
+            return None;
+        }
+        let (m_lo, m_hi) = (max(m_lo, 0) as u128, m_hi as u128);
+        let (k_first, k_last) = (k_first as u128, k_last as u128);
+        let first_step = |mut lo: u128, mut hi: u128, pred: &dyn Fn(u128) -> bool| {
+            // lo..hiでpredが初めて成り立つk。成り立たなければhi
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if pred(mid) {
+                    hi = mid;
+                } else {
+                    lo = mid + 1;
+                }
+            }
+            lo
+        };
+        let k0 = first_step(k_first, k_last + 1, &|k| self.minor_offset(k) >= m_lo);
+        let k1 = first_step(k0, k_last + 1, &|k| self.minor_offset(k) > m_hi);
+        if k0 == k1 {
+            return None;
+        }
+        Some((k0, k1 - 1))
+    }
+    fn for_each(&self, k0: u128, k1: u128, mut f: impl FnMut(i64, i64)) {
+        let mut m = self.minor_offset(k0);
+        if self.major == 0 {
+            let (x, y) = self.point(0, 0);
+            f(x, y);
+            return;
+        }
+        // t = 2 * k * minor + major - 1 - 2 * major * m は常に0..2 * majorに収まる
+        let p = k0 * self.minor;
+        let mut t = 2 * (p % self.major) + self.major - 1 - 2 * self.major * (m - p / self.major);
+        for k in k0..=k1 {
+            let (x, y) = self.point(k, m);
+            f(x, y);
+            t += 2 * self.minor;
+            if t >= 2 * self.major {
+                t -= 2 * self.major;
+                m += 1;
+            }
+        }
+    }
+}
+
+// Bresenhamのアルゴリズムで(x0, y0)から(x1, y1)までの点を両端を含めて列挙する
+fn for_each_line_point(x0: i64, y0: i64, x1: i64, y1: i64, f: impl FnMut(i64, i64)) {
+    let line = LineSteps::new(x0, y0, x1, y1);
+    line.for_each(0, line.major, f);
+}
+
+/// Same as draw_line, but fails with "Out of Range" instead of clipping when either
+/// endpoint is outside of buf.
+pub fn draw_line_unclipped<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    if !buf.is_in_x_range(x0)
+        || !buf.is_in_x_range(x1)
+        || !buf.is_in_y_range(y0)
+        || !buf.is_in_y_range(y1)
+    {
+        return Err("Out of Range");
+    }
+    // SAFETY: both endpoints are in range, so every point between them is in range too.
+    for_each_line_point(x0, y0, x1, y1, |x, y| unsafe {
+        unchecked_draw_point(buf, color, x, y)
+    });
+    Ok(())
+}
+
+/// Same as draw_line, with the endpoints as Points.
+pub fn draw_line_p<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    a: Point,
+    b: Point,
+) -> Result<()> {
+    draw_line(buf, color, a.x, a.y, b.x, b.y)
+}
+
+/// Draws a line `thickness` pixels wide, centered on the line that draw_line() would draw.
+/// Both endpoints are covered by a thickness x thickness square so that joined segments have
+/// no gaps at the corners. Thickness 1 draws exactly the same pixels as draw_line().
+/// As with draw_line(), the endpoints may be anywhere: only the part inside buf is drawn.
+pub fn draw_line_thick<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    x0: i64,
+    y0: i64,
+    x1: i64,
+    y1: i64,
+    thickness: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    if thickness <= 0 {
+        return Err("thickness must be positive");
+    }
+    let offset = (thickness - 1) / 2;
+    for (x, y) in [(x0, y0), (x1, y1)] {
+        let (x, y) = (x.saturating_sub(offset), y.saturating_sub(offset));
+        fill_rect_clipped(buf, color, x, y, thickness, thickness)?;
+    }
+    // 各点は線と垂直な方向(短い方の軸)にだけ広げるので、中心線が短い方の軸で
+    // thicknessだけ外側にあっても描画範囲に掛かる。その方向だけ広げた範囲で
+    // draw_lineと同じように切り詰める。長い方の軸の歩数は描画範囲の幅を超えない
+    let line = LineSteps::new(x0, y0, x1, y1);
+    let x_major = line.x_major;
+    let (cx0, cy0, cx1, cy1) = buf.clip_bounds();
+    let t = thickness;
+    let bounds = if x_major {
+        (cx0, cy0.saturating_sub(t), cx1, cy1.saturating_add(t))
+    } else {
+        (cx0.saturating_sub(t), cy0, cx1.saturating_add(t), cy1)
+    };
+    let Some((k0, k1)) = line.clip(bounds) else {
+        return Ok(());
+    };
+    line.for_each(k0, k1, |x, y| {
+        let _ = if x_major {
+            fill_rect_clipped(buf, color, x, y.saturating_sub(offset), 1, thickness)
+        } else {
+            fill_rect_clipped(buf, color, x.saturating_sub(offset), y, thickness, 1)
+        };
+    });
+    Ok(())
+}
+
+/// Draws only the outline of the rectangle. Every pixel of the outline is drawn exactly once.
+pub fn draw_rect<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    if w <= 0 || h <= 0 {
+        return Err("Out of Range");
+    }
+    let (x_last, y_last) = check_rect_range(buf, px, py, w, h)?;
+    // 上下の辺は四隅を含めて描き、左右の辺は四隅を除いて描く
+    draw_line(buf, color, px, py, x_last, py)?;
+    if h == 1 {
+        return Ok(());
+    }
+    draw_line(buf, color, px, y_last, x_last, y_last)?;
+    if h == 2 {
+        return Ok(());
+    }
+    draw_line(buf, color, px, py + 1, px, y_last - 1)?;
+    if w == 1 {
+        return Ok(());
+    }
+    draw_line(buf, color, x_last, py + 1, x_last, y_last - 1)
+}
+
+/// Draws a rectangle outline `thickness` pixels wide. The border grows inward from (px, py, w, h).
+pub fn draw_rect_with_thickness<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    px: i64,
+    py: i64,
+    w: i64,
+    h: i64,
+    thickness: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    if thickness <= 0 {
+        return Err("Out of Range");
+    }
+    draw_rect(buf, color, px, py, w, h)?;
+    for i in 1..thickness {
+        let (iw, ih) = (w - 2 * i, h - 2 * i);
+        if iw <= 0 || ih <= 0 {
+            break;
+        }
+        draw_rect(buf, color, px + i, py + i, iw, ih)?;
+    }
+    Ok(())
+}
+
+// floor(sqrt(v))
+fn isqrt(v: i64) -> i64 {
+    if v < 2 {
+        return v;
+    }
+    // sqrt(v)以上の2の累乗から始めて、ニュートン法で単調に減らしていく
+    let mut x = 1i64 << ((64 - v.leading_zeros() + 1) / 2);
+    loop {
+        let y = (x + v / x) / 2;
+        if y >= x {
+            return x;
+        }
+        x = y;
+    }
+}
+
+// 円が描画可能な範囲と重ならない場合はtrue
+fn is_circle_off_screen<T: Bitmap + ?Sized>(buf: &T, cx: i64, cy: i64, r: i64) -> bool {
+    let (x0, y0, x1, y1) = buf.clip_bounds();
+    cx.saturating_add(r) < x0
+        || cy.saturating_add(r) < y0
+        || cx.saturating_sub(r) >= x1
+        || cy.saturating_sub(r) >= y1
+}
+
+/// Draws a circle outline of radius `r` centered at (cx, cy) with the integer midpoint algorithm.
+/// The parts outside of buf are clipped. r == 0 draws a single point.
+pub fn draw_circle<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    cx: i64,
+    cy: i64,
+    r: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    if r < 0 {
+        return Err("Out of Range");
+    }
+    r.checked_mul(r).ok_or("Out of Range")?;
+    if is_circle_off_screen(buf, cx, cy, r) {
+        return Ok(());
+    }
+    let mut x = r;
+    let mut y = 0;
+    let mut err = 1 - r;
+    while x >= y {
+        let points = [
+            (x, y),
+            (-x, y),
+            (x, -y),
+            (-x, -y),
+            (y, x),
+            (-y, x),
+            (y, -x),
+            (-y, -x),
+        ];
+        for (i, (dx, dy)) in points.iter().enumerate() {
+            // 軸上や45度の位置では対称な点が重なるので、同じ画素を2回描かないようにする
+            if points[..i].contains(&(*dx, *dy)) {
+                continue;
+            }
+            if let (Some(x), Some(y)) = (cx.checked_add(*dx), cy.checked_add(*dy)) {
+                let _ = draw_point(buf, color, x, y);
+            }
+        }
+        y += 1;
+        if err < 0 {
+            err += 2 * y + 1;
+        } else {
+            x -= 1;
+            err += 2 * (y - x) + 1;
+        }
+    }
+    Ok(())
+}
+
+// 三角形の頂点の座標の上限。辺の式の値がi128に余裕を持って収まるようにする
+const TRIANGLE_COORD_LIMIT: u64 = 1 << 40;
+
+// 辺a→bに対してpがどちら側にあるか。頂点を時計回り(yが下向き)に並べると内側が正になる
+fn edge_function(a: (i64, i64), b: (i64, i64), p: (i64, i64)) -> i128 {
+    let (ax, ay, bx, by) = (a.0 as i128, a.1 as i128, b.0 as i128, b.1 as i128);
+    (bx - ax) * (p.1 as i128 - ay) - (by - ay) * (p.0 as i128 - ax)
+}
+
+/// Fills the triangle with the given vertices. A pixel is filled if its coordinates are
+/// inside the triangle; pixels exactly on an edge follow the top-left rule, so triangles
+/// sharing an edge (e.g. a polygon fan) neither overlap nor leave gaps. The parts outside
+/// of buf are clipped. Collinear vertices draw the line between the outermost two, and
+/// three equal vertices draw a point. Coordinates beyond +-2^40 are out of range.
+pub fn fill_triangle<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    p0: (i64, i64),
+    p1: (i64, i64),
+    p2: (i64, i64),
+) -> Result<()> {
+    let color = color_u32(color);
+    for (x, y) in [p0, p1, p2] {
+        if x.unsigned_abs() > TRIANGLE_COORD_LIMIT || y.unsigned_abs() > TRIANGLE_COORD_LIMIT {
+            return Err("Out of Range");
+        }
+    }
+    let area = edge_function(p0, p1, p2);
+    if area == 0 {
+        // 面積がないときは、一番離れた2頂点を結ぶ線を描く
+        let span = |(a, b): ((i64, i64), (i64, i64))| max((a.0 - b.0).abs(), (a.1 - b.1).abs());
+        let (a, b) = [(p0, p1), (p1, p2), (p0, p2)]
+            .into_iter()
+            .max_by_key(|pair| span(*pair))
+            .unwrap_or((p0, p1));
+        return draw_line(buf, color, a.0, a.1, b.0, b.1);
+    }
+    let (p1, p2) = if area < 0 { (p2, p1) } else { (p1, p2) };
+    let (clip_x0, clip_y0, clip_x1, clip_y1) = buf.clip_bounds();
+    let x_begin = max(min(p0.0, min(p1.0, p2.0)), clip_x0);
+    let x_end = min(max(p0.0, max(p1.0, p2.0)), clip_x1 - 1);
+    let y_begin = max(min(p0.1, min(p1.1, p2.1)), clip_y0);
+    let y_end = min(max(p0.1, max(p1.1, p2.1)), clip_y1 - 1);
+    if x_begin > x_end || y_begin > y_end {
+        return Ok(());
+    }
+    // 各辺について、(x_begin, y_begin)での値と、x, yが1増えたときの増分を求める
+    // 上の辺と左の辺の上にある点だけを含めるように、それ以外の辺では1引いておく
+    let edges = [(p0, p1), (p1, p2), (p2, p0)].map(|(a, b)| {
+        let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+        let top_left = dy < 0 || (dy == 0 && dx > 0);
+        let bias = if top_left { 0 } else { -1 };
+        let value = edge_function(a, b, (x_begin, y_begin)) + bias;
+        (value, -(dy as i128), dx as i128)
+    });
+    let mut row = edges.map(|(value, _, _)| value);
+    for y in y_begin..=y_end {
+        let mut w = row;
+        for x in x_begin..=x_end {
+            if w.iter().all(|v| *v >= 0) {
+                // SAFETY: (x, y) is within the bounding box clipped to buf above.
+                unsafe { unchecked_draw_point(buf, color, x, y) };
+            }
+            for (v, (_, step_x, _)) in w.iter_mut().zip(edges.iter()) {
+                *v += step_x;
+            }
+        }
+        for (v, (_, _, step_y)) in row.iter_mut().zip(edges.iter()) {
+            *v += step_y;
+        }
+    }
+    Ok(())
+}
+
+/// Fills the disc of radius `r` centered at (cx, cy), one horizontal span per scanline.
+/// The parts outside of buf are clipped. r == 0 draws a single point.
+pub fn fill_circle<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    color: impl Into<Color>,
+    cx: i64,
+    cy: i64,
+    r: i64,
+) -> Result<()> {
+    let color = color_u32(color);
+    if r < 0 {
+        return Err("Out of Range");
+    }
+    // draw_circleの中点法と同じく、x^2 + y^2 <= r^2 + r の点を塗る
+    let limit = r
+        .checked_mul(r)
+        .and_then(|v| v.checked_add(r))
+        .ok_or("Out of Range")?;
+    if is_circle_off_screen(buf, cx, cy, r) {
+        return Ok(());
+    }
+    let (_, clip_y0, _, clip_y1) = buf.clip_bounds();
+    let y_begin = max(cy.saturating_sub(r), clip_y0);
+    let y_end = min(cy.saturating_add(r), clip_y1 - 1);
+    for y in y_begin..=y_end {
+        let dy = y - cy;
+        let half = isqrt(limit - dy * dy);
+        fill_rect_clipped(
+            buf,
+            color,
+            cx.saturating_sub(half),
+            y,
+            half.saturating_mul(2).saturating_add(1),
+            1,
+        )?;
+    }
+    Ok(())
+}
+
+const FONT_SOURCE: &str = include_str!("./font.txt");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FontSourceError {
+    /// 1-based line number in font.txt
+    pub line: usize,
+    pub reason: &'static str,
+}
+
+const fn hex_digit(b: u8) -> Option<u8> {
+    match b {
+        b'0'..=b'9' => Some(b - b'0'),
+        b'a'..=b'f' => Some(b - b'a' + 10),
+        b'A'..=b'F' => Some(b - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// Returns the first violation in a font.txt style source, if any.
+/// Each glyph is a `0xNN` header followed by exactly 16 rows of exactly 8 characters
+/// from {'*', '.', ' '}, and each code point may appear only once.
+/// parse_font_table()は正しい入力を前提にしているので、不正なグリフはここで検出する
+pub const fn font_source_error(src: &[u8]) -> Option<FontSourceError> {
+    let mut seen = [false; 256];
+    let mut rows_left = 0;
+    let mut line = 1;
+    let mut i = 0;
+    while i < src.len() {
+        let begin = i;
+        while i < src.len() && src[i] != b'\n' {
+            i += 1;
+        }
+        let end = i;
+        i += 1;
+        if rows_left > 0 {
+            if end - begin != 8 {
+                return Some(FontSourceError {
+                    line,
+                    reason: "glyph row must be exactly 8 characters",
+                });
+            }
+            let mut k = begin;
+            while k < end {
+                if !matches!(src[k], b'*' | b'.' | b' ') {
+                    return Some(FontSourceError {
+                        line,
+                        reason: "glyph row may only contain '*', '.' or ' '",
+                    });
+                }
+                k += 1;
+            }
+            rows_left -= 1;
+        } else if end - begin == 4 && src[begin] == b'0' && src[begin + 1] == b'x' {
+            let (Some(hi), Some(lo)) = (hex_digit(src[begin + 2]), hex_digit(src[begin + 3]))
+            else {
+                return Some(FontSourceError {
+                    line,
+                    reason: "invalid code point in glyph header",
+                });
+            };
+            let idx = (hi * 16 + lo) as usize;
+            if seen[idx] {
+                return Some(FontSourceError {
+                    line,
+                    reason: "duplicate code point",
+                });
+            }
+            seen[idx] = true;
+            rows_left = 16;
+        } else if end != begin {
+            return Some(FontSourceError {
+                line,
+                reason: "expected a 0xNN glyph header or an empty line",
+            });
+        }
+        line += 1;
+    }
+    if rows_left > 0 {
+        return Some(FontSourceError {
+            line,
+            reason: "glyph has fewer than 16 rows",
+        });
+    }
+    None
+}
+const FONT_ERROR_MESSAGE_LEN: usize = 128;
+
+// "src/font.txt:<line>: <reason>"をコンパイル時に組み立てる。長すぎる理由は切り詰める
+const fn font_error_message(e: FontSourceError) -> ([u8; FONT_ERROR_MESSAGE_LEN], usize) {
+    let mut buf = [0u8; FONT_ERROR_MESSAGE_LEN];
+    let mut len = 0;
+    let prefix = b"src/font.txt:";
+    while len < prefix.len() {
+        buf[len] = prefix[len];
+        len += 1;
+    }
+    let mut digits = [0u8; 20];
+    let mut n = 0;
+    let mut line = e.line;
+    loop {
+        digits[n] = b'0' + (line % 10) as u8;
+        n += 1;
+        line /= 10;
+        if line == 0 {
+            break;
+        }
+    }
+    while n > 0 {
+        n -= 1;
+        buf[len] = digits[n];
+        len += 1;
+    }
+    buf[len] = b':';
+    buf[len + 1] = b' ';
+    len += 2;
+    let reason = e.reason.as_bytes();
+    let mut k = 0;
+    while k < reason.len() && len < FONT_ERROR_MESSAGE_LEN {
+        buf[len] = reason[k];
+        len += 1;
+        k += 1;
+    }
+    (buf, len)
+}
+
+// font.txtが壊れていたら、行番号と理由を付けてビルドを失敗させる
+const _: () = if let Some(e) = font_source_error(FONT_SOURCE.as_bytes()) {
+    let (buf, len) = font_error_message(e);
+    let (message, _) = buf.split_at(len);
+    match core::str::from_utf8(message) {
+        Ok(message) => panic!("{}", message),
+        Err(_) => panic!("src/font.txt is malformed"),
+    }
+};
+
+/// Drawn for characters that are not in font.txt (everything above U+00FF).
+/// Each row is a bitmap of 8 pixels, MSB first, like the entries of FONT_TABLE.
+pub const TOFU_GLYPH: [u8; 16] = [
+    0x00, 0x7e, 0x42, 0x42, 0x42, 0x42, 0x42, 0x42, //
+    0x42, 0x42, 0x42, 0x42, 0x42, 0x42, 0x7e, 0x00, //
+];
+
+// font.txtをコンパイル時に解析して、1行を1バイトのビット列にした表を作る。
+// 形式はfont_source_errorで検査済みなので、ここでは正しい入力だけを考えればよい
+const fn parse_font_table(src: &[u8]) -> [[u8; 16]; 256] {
+    let mut table = [TOFU_GLYPH; 256];
+    let mut i = 0;
+    let mut idx = 0;
+    let mut row = 16;
+    while i < src.len() {
+        let begin = i;
+        while i < src.len() && src[i] != b'\n' {
+            i += 1;
+        }
+        let end = i;
+        i += 1;
+        if row < 16 {
+            let mut bits = 0u8;
+            let mut k = 0;
+            while k < 8 && begin + k < end {
+                if src[begin + k] == b'*' {
+                    bits |= 0x80 >> k;
+                }
+                k += 1;
+            }
+            table[idx][row] = bits;
+            row += 1;
+        } else if end - begin == 4 && src[begin] == b'0' && src[begin + 1] == b'x' {
+            if let (Some(hi), Some(lo)) = (hex_digit(src[begin + 2]), hex_digit(src[begin + 3])) {
+                idx = (hi * 16 + lo) as usize;
+                row = 0;
+            }
+        }
+    }
+    table
+}
+
+// staticは定数式から読めないので、解析結果はconstに置いて送り幅の表と共有する
+const FONT_GLYPHS: [[u8; 16]; 256] = parse_font_table(FONT_SOURCE.as_bytes());
+
+/// The built-in 8x16 font, indexed by code point. Parsed from font.txt at compile time.
+pub static FONT_TABLE: [[u8; 16]; 256] = FONT_GLYPHS;
+
+// プロポーショナル表示での空白の送り幅と、グリフ間の余白
+const PROPORTIONAL_SPACE_ADVANCE: u8 = 4;
+const PROPORTIONAL_GLYPH_SPACING: u8 = 1;
+// 点が1〜2列しかない句読点は詰めすぎると読みにくいので、送り幅を固定する
+const PROPORTIONAL_ADVANCE_OVERRIDES: [(u8, u8); 7] = [
+    (b' ', PROPORTIONAL_SPACE_ADVANCE),
+    (b'.', 4),
+    (b',', 4),
+    (b':', 4),
+    (b';', 4),
+    (b'\'', 4),
+    (b'!', 4),
+];
+
+// グリフ中で点が打たれている最も左と右の列
+const fn glyph_used_columns(rows: &[u8; 16]) -> Option<(u8, u8)> {
+    let mut bits = 0u8;
+    let mut y = 0;
+    while y < 16 {
+        bits |= rows[y];
+        y += 1;
+    }
+    if bits == 0 {
+        None
+    } else {
+        Some((bits.leading_zeros() as u8, 7 - bits.trailing_zeros() as u8))
+    }
+}
+
+// (送り幅, 描画位置のずれ)。送り幅を固定したグリフは、その幅の中央に置く
+const fn glyph_metrics(code: u8, rows: &[u8; 16]) -> (u8, i8) {
+    let mut advance = None;
+    let mut i = 0;
+    while i < PROPORTIONAL_ADVANCE_OVERRIDES.len() {
+        if PROPORTIONAL_ADVANCE_OVERRIDES[i].0 == code {
+            advance = Some(PROPORTIONAL_ADVANCE_OVERRIDES[i].1);
+        }
+        i += 1;
+    }
+    match (glyph_used_columns(rows), advance) {
+        (Some((l, r)), Some(advance)) => {
+            let padding = advance as i8 - (r - l + 1) as i8 - PROPORTIONAL_GLYPH_SPACING as i8;
+            (advance, padding / 2 - l as i8)
+        }
+        (Some((l, r)), None) => (r - l + 1 + PROPORTIONAL_GLYPH_SPACING, -(l as i8)),
+        (None, Some(advance)) => (advance, 0),
+        (None, None) => (PROPORTIONAL_SPACE_ADVANCE, 0),
+    }
+}
+
+const fn build_font_advance(table: &[[u8; 16]; 256]) -> ([u8; 256], [i8; 256]) {
+    let mut advance = [0u8; 256];
+    let mut bearing = [0i8; 256];
+    let mut c = 0;
+    while c < 256 {
+        (advance[c], bearing[c]) = glyph_metrics(c as u8, &table[c]);
+        c += 1;
+    }
+    (advance, bearing)
+}
+const FONT_METRICS: ([u8; 256], [i8; 256]) = build_font_advance(&FONT_GLYPHS);
+
+/// Advance width in pixels of each glyph of FONT_TABLE in the proportional mode:
+/// the glyph's used columns plus 1px spacing, or a fixed width for blanks and punctuation.
+pub static FONT_ADVANCE: [u8; 256] = FONT_METRICS.0;
+// 描画するときにセルの左端をずらす量。左側の空白列を詰める
+static FONT_BEARING: [i8; 256] = FONT_METRICS.1;
+// FONT_TABLEに無い文字はとうふを描くので、送り幅もとうふのもの
+const TOFU_METRICS: (u8, i8) = glyph_metrics(0, &TOFU_GLYPH);
+
+// 描画のホットパスはここを通る。文字列の解析はしない
+fn glyph_rows(c: char) -> &'static [u8; 16] {
+    match u8::try_from(c) {
+        Ok(c) => &FONT_TABLE[c as usize],
+        Err(_) => &TOFU_GLYPH,
+    }
+}
+
+/// The glyph of `c` in the built-in font as '*' / '.' pixels (kept for compatibility;
+/// drawing uses FONT_TABLE directly). None only for characters above U+00FF;
+/// a code point up to U+00FF that font.txt lacks comes back as TOFU_GLYPH.
+pub fn lookup_font(c: char) -> Option<[[char; 8]; 16]> {
+    let c = u8::try_from(c).ok()?;
+    Some(glyph_chars(&FONT_TABLE[c as usize]))
+}
+
+fn glyph_chars(rows: &[u8; 16]) -> [[char; 8]; 16] {
+    let mut glyph = [['.'; 8]; 16];
+    for (y, bits) in rows.iter().enumerate() {
+        for (x, pixel) in glyph[y].iter_mut().enumerate() {
+            if bits & (0x80 >> x) != 0 {
+                *pixel = '*';
+            }
+        }
+    }
+    glyph
+}
+
+/// Draws all 256 glyphs in a 16x16 grid with hex labels for the row and column,
+/// so that a broken glyph is easy to spot on screen.
+pub fn draw_font_demo<T: Bitmap + ?Sized>(buf: &mut T, x: i64, y: i64, color: impl Into<Color>) {
+    let color = color_u32(color);
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    for i in 0..16 {
+        let label = HEX[i as usize] as char;
+        draw_font_fg(buf, x + 24 + i * 16, y, color, label);
+        draw_font_fg(buf, x, y + 24 + i * 24, color, label);
+        draw_font_fg(buf, x + 8, y + 24 + i * 24, color, 'x');
+    }
+    for code in 0..=255u8 {
+        let (row, col) = ((code / 16) as i64, (code % 16) as i64);
+        draw_font_fg(buf, x + 24 + col * 16, y + 24 + row * 24, color, code as char);
+    }
+}
+
+/// Glyph that draw_font_fg_with draws for characters that are not in the given font,
+/// so that they still occupy their cells. (The built-in font uses TOFU_GLYPH instead.)
+pub const FALLBACK_GLYPH: char = '?';
+
+/// Number of 8x16 cells `c` occupies on screen.
+/// Every glyph we render (including TOFU_GLYPH) is one cell wide, so this is always 1
+/// and matches the serial output column by column. Wide glyphs would return 2 here.
+/// シリアル出力と画面表示で桁がずれないように、文字幅の計算は必ずここを通す
+pub fn char_cells(_c: char) -> u8 {
+    1
+}
+
+pub fn draw_font_fg<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    color: impl Into<Color>,
+    c: char,
+) {
+    let color = color_u32(color);
+    for (dy, bits) in glyph_rows(c).iter().enumerate() {
+        for dx in 0..8 {
+            if bits & (0x80 >> dx) == 0 {
+                continue;
+            }
+            if let (Some(x), Some(y)) = (x.checked_add(dx), y.checked_add(dy as i64)) {
+                let _ = draw_point(buf, color, x, y);
+            }
+        }
+    }
+}
+
+/// draw_font_fg with a font other than the built-in font.txt (e.g. a PSF font).
+pub fn draw_font_fg_with<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    font: &Font,
+    x: i64,
+    y: i64,
+    color: impl Into<Color>,
+    c: char,
+) {
+    let color = color_u32(color);
+    let Some(glyph) = font.glyph(c).or_else(|| font.glyph(FALLBACK_GLYPH)) else {
+        return;
+    };
+    for dy in 0..font.height() {
+        for dx in 0..font.width() {
+            if !font.is_set(glyph, dx, dy) {
+                continue;
+            }
+            if let (Some(x), Some(y)) = (x.checked_add(dx), y.checked_add(dy)) {
+                let _ = draw_point(buf, color, x, y);
+            }
+        }
+    }
+}
+
+/// draw_str_fg with `font`. Returns the advanced width in pixels.
+pub fn draw_str_fg_with<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    font: &Font,
+    x: i64,
+    y: i64,
+    color: impl Into<Color>,
+    s: &str,
+) -> i64 {
+    let color = color_u32(color);
+    let mut advanced: i64 = 0;
+    for c in s.chars() {
+        let Some(cx) = x.checked_add(advanced) else {
+            break;
+        };
+        draw_font_fg_with(buf, font, cx, y, color, c);
+        advanced = advanced.saturating_add(font.width());
+    }
+    advanced
+}
+
+/// draw_font_fg with each font pixel replicated into a scale x scale block.
+/// scale <= 0 is treated as 1, and scale 1 is identical to draw_font_fg.
+pub fn draw_font_scaled<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    color: impl Into<Color>,
+    c: char,
+    scale: i64,
+) {
+    let color = color_u32(color);
+    let scale = max(scale, 1);
+    for (dy, bits) in glyph_rows(c).iter().enumerate() {
+        for dx in 0..8i64 {
+            if bits & (0x80 >> dx) == 0 {
+                continue;
+            }
+            let px = dx.checked_mul(scale).and_then(|v| x.checked_add(v));
+            let py = (dy as i64).checked_mul(scale).and_then(|v| y.checked_add(v));
+            if let (Some(px), Some(py)) = (px, py) {
+                let _ = fill_rect_clipped(buf, color, px, py, scale, scale);
+            }
+        }
+    }
+}
+
+/// draw_str_fg with scaled glyphs; the cursor advances 8 * scale pixels per cell.
+pub fn draw_str_fg_scaled<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    color: impl Into<Color>,
+    s: &str,
+    scale: i64,
+) {
+    let color = color_u32(color);
+    let scale = max(scale, 1);
+    let mut cells: i64 = 0;
+    for c in s.chars() {
+        let Some(cx) = cells
+            .checked_mul(8)
+            .and_then(|v| v.checked_mul(scale))
+            .and_then(|dx| x.checked_add(dx))
+        else {
+            break;
+        };
+        draw_font_scaled(buf, cx, y, color, c, scale);
+        cells += char_cells(c) as i64;
+    }
+}
+
+/// Paints the whole 8x16 cell of `c`: glyph pixels with fg and everything else with bg,
+/// so that it can overwrite text drawn before without leaving ghosts.
+pub fn draw_char<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    fg: impl Into<Color>,
+    bg: impl Into<Color>,
+    c: char,
+) {
+    let fg = color_u32(fg);
+    let bg = color_u32(bg);
+    for (dy, bits) in glyph_rows(c).iter().enumerate() {
+        for dx in 0..8 {
+            let color = if bits & (0x80 >> dx) != 0 { fg } else { bg };
+            if let (Some(x), Some(y)) = (x.checked_add(dx), y.checked_add(dy as i64)) {
+                let _ = draw_point(buf, color, x, y);
+            }
+        }
+    }
+}
+
+/// draw_str_fg with a background color (see draw_char).
+pub fn draw_str<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    fg: impl Into<Color>,
+    bg: impl Into<Color>,
+    s: &str,
+) {
+    let fg = color_u32(fg);
+    let bg = color_u32(bg);
+    let mut cells: i64 = 0;
+    for c in s.chars() {
+        let Some(cx) = cells.checked_mul(8).and_then(|dx| x.checked_add(dx)) else {
+            break;
+        };
+        draw_char(buf, cx, y, fg, bg, c);
+        cells += char_cells(c) as i64;
+    }
+}
+
+/// Same result as draw_str, but paints each cell with fill_rect first and then draws
+/// only the glyph pixels on top of it.
+pub fn draw_str_bg<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    fg: impl Into<Color>,
+    bg: impl Into<Color>,
+    s: &str,
+) {
+    let fg = color_u32(fg);
+    let bg = color_u32(bg);
+    let mut cells: i64 = 0;
+    for c in s.chars() {
+        let Some(cx) = cells.checked_mul(8).and_then(|dx| x.checked_add(dx)) else {
+            break;
+        };
+        let _ = fill_rect_clipped(buf, bg, cx, y, 8, 16);
+        draw_font_fg(buf, cx, y, fg, c);
+        cells += char_cells(c) as i64;
+    }
+}
+
+/// Pixel width of `s` drawn with draw_str_fg. For multi-line strings, the width of
+/// the longest line.
+pub fn measure_str(s: &str) -> i64 {
+    s.split('\n')
+        .map(|line| {
+            line.chars()
+                .fold(0i64, |w, c| w.saturating_add(char_cells(c) as i64 * 8))
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the advanced width in pixels (= measure_str(s) for a single line), so that
+/// callers can chain draws.
+pub fn draw_str_fg<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    color: impl Into<Color>,
+    s: &str,
+) -> i64 {
+    let color = color_u32(color);
+    let mut cells: i64 = 0;
+    for c in s.chars() {
+        let Some(cx) = cells.checked_mul(8).and_then(|dx| x.checked_add(dx)) else {
+            break;
+        };
+        draw_font_fg(buf, cx, y, color, c);
+        cells += char_cells(c) as i64;
+    }
+    cells.saturating_mul(8)
+}
+
+/// What draw_str_fg_bounded does with text that doesn't fit in the given width.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextOverflow {
+    /// Characters that don't fit entirely are not drawn.
+    Clip,
+    /// Continues on the next line (16px below, starting at x again). '\n' also starts
+    /// a new line. Drawing stops at the bottom of buf.
+    Wrap,
+}
+
+/// draw_str_fg limited to `max_width` pixels from x (and to the right edge of buf).
+/// Returns the number of characters drawn.
+pub fn draw_str_fg_bounded<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    color: impl Into<Color>,
+    s: &str,
+    max_width: i64,
+    overflow: TextOverflow,
+) -> usize {
+    let color = color_u32(color);
+    let (_, _, clip_x1, clip_y1) = buf.clip_bounds();
+    let right = min(x.saturating_add(max(max_width, 0)), clip_x1);
+    let (mut cx, mut cy) = (x, y);
+    let mut drawn = 0;
+    for c in s.chars() {
+        let advance = 8 * char_cells(c) as i64;
+        let fits = cx.checked_add(advance).is_some_and(|end| end <= right);
+        if overflow == TextOverflow::Wrap && (c == '\n' || !fits) {
+            cx = x;
+            cy = match cy.checked_add(16) {
+                Some(cy) if cy < clip_y1 => cy,
+                _ => break,
+            };
+            if c == '\n' {
+                continue;
+            }
+            if x.checked_add(advance).map_or(true, |end| end > right) {
+                // 1文字も入らない幅
+                break;
+            }
+        } else if !fits {
+            break;
+        }
+        draw_font_fg(buf, cx, cy, color, c);
+        drawn += 1;
+        cx += advance;
+    }
+    drawn
+}
+
+// (送り幅, 描画位置のずれ)を表から引く
+fn proportional_metrics(c: char) -> (i64, i64) {
+    let (advance, bearing) = match u8::try_from(c) {
+        Ok(c) => (FONT_ADVANCE[c as usize], FONT_BEARING[c as usize]),
+        Err(_) => TOFU_METRICS,
+    };
+    (advance as i64, bearing as i64)
+}
+
+/// Horizontal advance of `c` in the proportional mode (see FONT_ADVANCE).
+/// Characters outside of the font advance by the width of TOFU_GLYPH.
+pub fn glyph_advance_proportional(c: char) -> i64 {
+    proportional_metrics(c).0
+}
+
+pub fn text_width_proportional(s: &str) -> i64 {
+    s.chars()
+        .map(glyph_advance_proportional)
+        .fold(0, |w, a| w.saturating_add(a))
+}
+
+/// Draws `s` with per-glyph advance widths instead of fixed 8px cells and returns the x
+/// right after the last glyph. The console stays monospace; this is for UI labels.
+pub fn draw_str_proportional<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    color: impl Into<Color>,
+    s: &str,
+) -> i64 {
+    let color = color_u32(color);
+    let mut cx = x;
+    for c in s.chars() {
+        let (advance, bearing) = proportional_metrics(c);
+        if let Some(gx) = cx.checked_add(bearing) {
+            draw_font_fg(buf, gx, y, color, c);
+        }
+        cx = cx.saturating_add(advance);
+    }
+    cx
+}
+
+/// A rectangle of a parent bitmap that is itself a Bitmap: (0, 0) is (x, y) of the parent
+/// and the size is (w, h). Every drawing function works inside it unchanged, and since
+/// is_in_*_range only accepts points in the rectangle, nothing drawn through it can
+/// touch the parent outside of it. A region of a region works as well.
+pub struct BitmapRegion<'a, T: Bitmap + ?Sized> {
+    parent: &'a mut T,
+    x: i64,
+    y: i64,
+    width: i64,
+    height: i64,
+}
+impl<'a, T: Bitmap + ?Sized> BitmapRegion<'a, T> {
+    /// The rectangle must be inside parent, as with fill_rect.
+    pub fn new(parent: &'a mut T, x: i64, y: i64, w: i64, h: i64) -> Result<Self> {
+        check_rect_range(parent, x, y, w, h)?;
+        Ok(Self {
+            parent,
+            x,
+            y,
+            width: w,
+            height: h,
+        })
+    }
+}
+impl<T: Bitmap + ?Sized> Bitmap for BitmapRegion<'_, T> {
+    fn bytes_per_pixel(&self) -> i64 {
+        self.parent.bytes_per_pixel()
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.parent.pixels_per_line()
+    }
+    fn width(&self) -> i64 {
+        self.width
+    }
+    fn height(&self) -> i64 {
+        self.height
+    }
+    // 先頭を親の(x, y)にずらすだけで、行の間隔は親と同じなので座標変換が済む
+    fn buf(&self) -> *const u8 {
+        // SAFETY: (x, y) is inside parent (checked in new()).
+        unsafe { self.parent.unchecked_pixel_at(self.x, self.y) as *const u8 }
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        // SAFETY: (x, y) is inside parent (checked in new()).
+        unsafe { self.parent.unchecked_pixel_at_mut(self.x, self.y) as *mut u8 }
+    }
+    // 親が座標を変換するBitmap(RotatedBitmapなど)でも正しく動くように、画素は親に求める
+    unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u32 {
+        crate::check_gfx!(
+            self.is_in_x_range(x) && self.is_in_y_range(y),
+            "check_gfx: pixel out of range"
+        );
+        self.parent.unchecked_pixel_at_mut(self.x + x, self.y + y)
+    }
+    unsafe fn unchecked_pixel_at(&self, x: i64, y: i64) -> *const u32 {
+        crate::check_gfx!(
+            self.is_in_x_range(x) && self.is_in_y_range(y),
+            "check_gfx: pixel out of range"
+        );
+        self.parent.unchecked_pixel_at(self.x + x, self.y + y)
+    }
+    fn has_contiguous_rows(&self) -> bool {
+        self.parent.has_contiguous_rows()
+    }
+}
+
+/// Clip rectangle for Clipped, in the coordinates of the underlying bitmap.
+pub type ClipRect = Rect;
+
+/// A bitmap with the same coordinates as the underlying one, but only the part inside
+/// `clip` is drawable. Unlike BitmapRegion nothing is translated, and the drawing
+/// functions that clip (fill_rect_clipped, draw_line, draw_str_fg,
+/// transfer_rect, ...) silently skip the pixels outside of the clip rectangle.
+pub struct Clipped<'a, T: Bitmap + ?Sized> {
+    inner: &'a mut T,
+    clip: ClipRect,
+}
+impl<'a, T: Bitmap + ?Sized> Clipped<'a, T> {
+    pub fn new(inner: &'a mut T, clip: ClipRect) -> Self {
+        Self { inner, clip }
+    }
+}
+impl<T: Bitmap + ?Sized> Bitmap for Clipped<'_, T> {
+    fn bytes_per_pixel(&self) -> i64 {
+        self.inner.bytes_per_pixel()
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.inner.pixels_per_line()
+    }
+    fn width(&self) -> i64 {
+        self.inner.width()
+    }
+    fn height(&self) -> i64 {
+        self.inner.height()
+    }
+    fn buf(&self) -> *const u8 {
+        self.inner.buf()
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.inner.buf_mut()
+    }
+    unsafe fn unchecked_pixel_at_mut(&mut self, x: i64, y: i64) -> *mut u32 {
+        crate::check_gfx!(
+            self.is_in_x_range(x) && self.is_in_y_range(y),
+            "check_gfx: pixel out of range"
+        );
+        self.inner.unchecked_pixel_at_mut(x, y)
+    }
+    unsafe fn unchecked_pixel_at(&self, x: i64, y: i64) -> *const u32 {
+        crate::check_gfx!(
+            self.is_in_x_range(x) && self.is_in_y_range(y),
+            "check_gfx: pixel out of range"
+        );
+        self.inner.unchecked_pixel_at(x, y)
+    }
+    fn has_contiguous_rows(&self) -> bool {
+        self.inner.has_contiguous_rows()
+    }
+    // 下のビットマップの描画可能な範囲との共通部分。入れ子にしても狭まる一方になる
+    fn clip_bounds(&self) -> (i64, i64, i64, i64) {
+        let c = self.clip.clamp_to(&clip_rect(&*self.inner));
+        (c.x, c.y, c.right(), c.bottom())
+    }
+}
+
+/// An off-screen bitmap that owns its pixels (on the heap).
+/// Compose a frame here with the usual drawing functions, then flush_to() the VRAM
+/// in one go so that half-drawn frames never become visible.
+pub struct BitmapBuffer {
+    buf: Vec<u32>,
+    width: i64,
+    height: i64,
+    pixels_per_line: i64,
+}
+impl BitmapBuffer {
+    /// Negative sizes are treated as 0.
+    pub fn new(width: i64, height: i64) -> Self {
+        let width = max(width, 0);
+        Self::with_pixels_per_line(width, height, width)
+    }
+    /// A buffer with the same width, height and pixels_per_line as `bitmap`,
+    /// so that present() accepts it. Panics if the frame is too large; see try_new_like().
+    pub fn new_like<T: Bitmap + ?Sized>(bitmap: &T) -> Self {
+        Self::with_pixels_per_line(bitmap.width(), bitmap.height(), bitmap.pixels_per_line())
+    }
+    /// Same as new_like(), but returns an error instead of panicking when the
+    /// heap can't hold the whole frame (e.g. QEMU with -m 64).
+    pub fn try_new_like<T: Bitmap + ?Sized>(bitmap: &T) -> Result<Self> {
+        Self::try_with_pixels_per_line(bitmap.width(), bitmap.height(), bitmap.pixels_per_line())
+    }
+    fn with_pixels_per_line(width: i64, height: i64, pixels_per_line: i64) -> Self {
+        Self::try_with_pixels_per_line(width, height, pixels_per_line)
+            .expect("BitmapBuffer: cannot allocate the buffer")
+    }
+    fn try_with_pixels_per_line(width: i64, height: i64, pixels_per_line: i64) -> Result<Self> {
+        let width = max(width, 0);
+        let height = max(height, 0);
+        let pixels_per_line = max(pixels_per_line, width);
+        let len = pixels_per_line
+            .checked_mul(height)
+            .and_then(|n| usize::try_from(n).ok())
+            .ok_or("BitmapBuffer: size overflow")?;
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(len)
+            .or(Err("BitmapBuffer: out of memory"))?;
+        buf.resize(len, 0);
+        Ok(Self {
+            buf,
+            width,
+            height,
+            pixels_per_line,
+        })
+    }
+    /// Copies the whole buffer to the top-left corner of dst, row by row.
+    pub fn flush_to<T: Bitmap + ?Sized>(&self, dst: &mut T) -> Result<()> {
+        transfer_rect(dst, self, 0, 0, 0, 0, self.width, self.height)
+    }
+}
+impl Bitmap for BitmapBuffer {
+    fn bytes_per_pixel(&self) -> i64 {
+        4
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.pixels_per_line
+    }
+    fn width(&self) -> i64 {
+        self.width
+    }
+    fn height(&self) -> i64 {
+        self.height
+    }
+    fn buf(&self) -> *const u8 {
+        self.buf.as_ptr() as *const u8
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.buf.as_mut_ptr() as *mut u8
+    }
+}
+
+/// Off-screen frame that mirrors the VRAM it is presented to (see BitmapBuffer::new_like).
+pub type BackBuffer = BitmapBuffer;
+
+/// Copies the whole frame in src to dst.
+/// src must have exactly the same width, height and pixels_per_line as dst
+/// (create it with BackBuffer::new_like(dst)); otherwise Err is returned and dst is untouched.
+pub fn present<T: Bitmap + ?Sized>(src: &BackBuffer, dst: &mut T) -> Result<()> {
+    if src.width() != dst.width()
+        || src.height() != dst.height()
+        || src.pixels_per_line() != dst.pixels_per_line()
+        || dst.bytes_per_pixel() != 4
+    {
+        return Err("BackBuffer does not match the destination");
+    }
+    // dstがBitmapRegionの場合、行末の余白は親の画素なので1行ずつコピーする
+    transfer_rect(dst, src, 0, 0, 0, 0, src.width(), src.height())
+}
+
+/// A grid of equally sized sprites packed into one 0x00RRGGBB image
+/// (0xAARRGGBB with with_alpha()).
+/// 1枚の画像に格子状に並べたスプライトを、セル単位で描画する
+pub struct SpriteSheet<'a> {
+    pixels: &'a [u32],
+    width: i64,
+    height: i64,
+    cell_width: i64,
+    cell_height: i64,
+    color_key: Option<u32>,
+    alpha: bool,
+}
+impl<'a> SpriteSheet<'a> {
+    pub fn new(
+        pixels: &'a [u32],
+        width: i64,
+        height: i64,
+        cell_width: i64,
+        cell_height: i64,
+    ) -> Result<Self> {
+        if width <= 0 || height <= 0 || cell_width <= 0 || cell_height <= 0 {
+            return Err("Invalid sprite sheet size");
+        }
+        if cell_width > width || cell_height > height {
+            return Err("Cell is larger than the sprite sheet");
+        }
+        let len = width.checked_mul(height).ok_or("Out of Range")?;
+        if (pixels.len() as i64) < len {
+            return Err("Sprite sheet buffer too small");
+        }
+        Ok(Self {
+            pixels,
+            width,
+            height,
+            cell_width,
+            cell_height,
+            color_key: None,
+            alpha: false,
+        })
+    }
+    /// Pixels with this color are skipped when drawing. 指定した色は透明として扱う
+    pub fn with_color_key(mut self, color_key: u32) -> Self {
+        self.color_key = Some(color_key);
+        self
+    }
+    /// Treats the pixels as 0xAARRGGBB and blends them over dst with blend_pixel().
+    /// Alpha 0 is skipped like the color key, 255 is copied as is.
+    pub fn with_alpha(mut self) -> Self {
+        self.alpha = true;
+        self
+    }
+    pub fn columns(&self) -> i64 {
+        self.width / self.cell_width
+    }
+    pub fn rows(&self) -> i64 {
+        self.height / self.cell_height
+    }
+    pub fn cell_count(&self) -> i64 {
+        self.columns() * self.rows()
+    }
+    fn is_transparent(&self, color: u32) -> bool {
+        self.color_key == Some(color) || (self.alpha && color >> 24 == 0)
+    }
+    // dstの画素dにsrcの画素を重ねた結果
+    fn composite(&self, d: u32, src: u32) -> u32 {
+        if !self.alpha {
+            return src;
+        }
+        match (src >> 24) as u8 {
+            255 => src & 0xffffff,
+            a => blend_pixel(d, src & 0xffffff, a),
+        }
+    }
+    /// Draws the cell at (col, row) with its top-left corner at (x, y) on dst.
+    /// The parts outside of dst are clipped.
+    pub fn draw_cell<T: Bitmap + ?Sized>(
+        &self,
+        dst: &mut T,
+        x: i64,
+        y: i64,
+        col: i64,
+        row: i64,
+    ) -> Result<()> {
+        if !(0..self.columns()).contains(&col) || !(0..self.rows()).contains(&row) {
+            return Err("Out of Range");
+        }
+        let (clip_x0, _, clip_x1, _) = dst.clip_bounds();
+        // セル内でdstの描画可能な範囲に収まる列の範囲
+        let cx_begin = max(0, clip_x0.saturating_sub(x));
+        let cx_end = min(self.cell_width, clip_x1.saturating_sub(x));
+        if cx_begin >= cx_end {
+            return Ok(());
+        }
+        for cy in 0..self.cell_height {
+            let Some(dy) = y.checked_add(cy) else {
+                break;
+            };
+            if !dst.is_in_y_range(dy) {
+                continue;
+            }
+            let src_begin = (row * self.cell_height + cy) * self.width + col * self.cell_width;
+            let src_row = &self.pixels[src_begin as usize..][..self.cell_width as usize];
+            let contiguous = dst.has_contiguous_rows();
+            // SAFETY: (x + cx_begin, dy) is in range since cx_begin..cx_end is clipped to dst.
+            let dst_row = unsafe { dst.unchecked_pixel_at_mut(x + cx_begin, dy) };
+            // 透明な画素の連続はまとめて読み飛ばし、不透明な連続だけを書き込む
+            let mut cx = cx_begin;
+            while cx < cx_end {
+                if self.is_transparent(src_row[cx as usize]) {
+                    cx += 1;
+                    continue;
+                }
+                let run_begin = cx;
+                while cx < cx_end && !self.is_transparent(src_row[cx as usize]) {
+                    cx += 1;
+                }
+                for i in run_begin..cx {
+                    unsafe {
+                        let p = if contiguous {
+                            dst_row.add((i - cx_begin) as usize)
+                        } else {
+                            dst.unchecked_pixel_at_mut(x + i, dy)
+                        };
+                        *p = self.composite(*p, src_row[i as usize]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Draws a w x h sprite (pixels.len() must be exactly w * h) at (x, y), skipping pixels
+/// equal to `transparent`. The parts outside of dst are clipped.
+pub fn draw_sprite<T: Bitmap + ?Sized>(
+    dst: &mut T,
+    x: i64,
+    y: i64,
+    w: i64,
+    h: i64,
+    pixels: &[u32],
+    transparent: Option<u32>,
+) -> Result<()> {
+    if w.checked_mul(h) != Some(pixels.len() as i64) {
+        return Err("Sprite size does not match the pixels");
+    }
+    let mut sprite = SpriteSheet::new(pixels, w, h, w, h)?;
+    sprite.color_key = transparent;
+    sprite.draw_cell(dst, x, y, 0, 0)
+}
+
+/// Copies a decoded image, `img_w` x `img_h` pixels packed row major as 0x00RRGGBB, to
+/// (x, y) of buf. The parts outside of buf are clipped. `img.len()` must be exactly
+/// img_w * img_h; an empty image draws nothing.
+pub fn draw_image_raw<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    x: i64,
+    y: i64,
+    img: &[u32],
+    img_w: i64,
+    img_h: i64,
+) -> Result<()> {
+    if img_w < 0 || img_h < 0 || img_w.checked_mul(img_h) != Some(img.len() as i64) {
+        return Err("Image size does not match the pixels");
+    }
+    if img.is_empty() {
+        return Ok(());
+    }
+    draw_sprite(buf, x, y, img_w, img_h, img, None)
+}
+
+// 呼び出し側のu32のスライスをw x hのBitmapとして扱う
+struct SliceBitmap<'a> {
+    pixels: &'a mut [u32],
+    width: i64,
+    height: i64,
+}
+impl Bitmap for SliceBitmap<'_> {
+    fn bytes_per_pixel(&self) -> i64 {
+        4
+    }
+    fn pixels_per_line(&self) -> i64 {
+        self.width
+    }
+    fn width(&self) -> i64 {
+        self.width
+    }
+    fn height(&self) -> i64 {
+        self.height
+    }
+    fn buf(&self) -> *const u8 {
+        self.pixels.as_ptr() as *const u8
+    }
+    fn buf_mut(&mut self) -> *mut u8 {
+        self.pixels.as_mut_ptr() as *mut u8
+    }
+}
+
+/// Copies the w x h region at (x, y) of src into `out` (row major, exactly w * h long),
+/// e.g. to restore what was under a cursor with draw_sprite(.., None) later.
+/// Entries for pixels outside of src are left as they are.
+pub fn save_rect<T: Bitmap + ?Sized>(
+    src: &T,
+    x: i64,
+    y: i64,
+    w: i64,
+    h: i64,
+    out: &mut [u32],
+) -> Result<()> {
+    if w <= 0 || h <= 0 || w.checked_mul(h) != Some(out.len() as i64) {
+        return Err("Buffer size does not match the rect");
+    }
+    let mut dst = SliceBitmap {
+        pixels: out,
+        width: w,
+        height: h,
+    };
+    transfer_rect(&mut dst, src, x, y, 0, 0, w, h)
+}
+
+const CURSOR_WIDTH: i64 = 8;
+const CURSOR_HEIGHT: i64 = 11;
+const CURSOR_PIXELS: usize = (CURSOR_WIDTH * CURSOR_HEIGHT) as usize;
+// 0x00RRGGBBとしてはありえない値なので、背景の色とは衝突しない
+const CURSOR_TRANSPARENT: u32 = 0xff00_0000;
+// '*'が白、'o'が黒の縁取り、'.'が透明
+#[rustfmt::skip]
+const CURSOR_SHAPE: [[char; CURSOR_WIDTH as usize]; CURSOR_HEIGHT as usize] = [
+    ['o', '.', '.', '.', '.', '.', '.', '.'],
+    ['o', 'o', '.', '.', '.', '.', '.', '.'],
+    ['o', '*', 'o', '.', '.', '.', '.', '.'],
+    ['o', '*', '*', 'o', '.', '.', '.', '.'],
+    ['o', '*', '*', '*', 'o', '.', '.', '.'],
+    ['o', '*', '*', '*', '*', 'o', '.', '.'],
+    ['o', '*', '*', '*', '*', '*', 'o', '.'],
+    ['o', '*', '*', 'o', 'o', 'o', 'o', 'o'],
+    ['o', '*', 'o', '*', 'o', '.', '.', '.'],
+    ['o', 'o', '.', 'o', '*', 'o', '.', '.'],
+    ['o', '.', '.', '.', 'o', 'o', '.', '.'],
+];
+
+/// An arrow mouse cursor that remembers the pixels under it, so that moving it around
+/// never damages what is drawn on the bitmap. Near the edges the cursor is clipped.
+pub struct Cursor {
+    sprite: [u32; CURSOR_PIXELS],
+    saved: [u32; CURSOR_PIXELS],
+    position: Option<(i64, i64)>,
+}
+impl Cursor {
+    pub fn new() -> Self {
+        let mut sprite = [CURSOR_TRANSPARENT; CURSOR_PIXELS];
+        for (i, c) in CURSOR_SHAPE.iter().flatten().enumerate() {
+            match c {
+                '*' => sprite[i] = Color::WHITE.to_u32(),
+                'o' => sprite[i] = Color::BLACK.to_u32(),
+                _ => {}
+            }
+        }
+        Self {
+            sprite,
+            saved: [0; CURSOR_PIXELS],
+            position: None,
+        }
+    }
+    /// Where the cursor is currently drawn, if it is.
+    pub fn position(&self) -> Option<(i64, i64)> {
+        self.position
+    }
+    /// Saves the background at (x, y) and draws the cursor there. If the cursor is
+    /// already drawn somewhere, it is erased first, so this also moves it.
+    pub fn draw<T: Bitmap + ?Sized>(&mut self, buf: &mut T, x: i64, y: i64) -> Result<()> {
+        self.erase(buf)?;
+        save_rect(buf, x, y, CURSOR_WIDTH, CURSOR_HEIGHT, &mut self.saved)?;
+        self.position = Some((x, y));
+        let (w, h) = (CURSOR_WIDTH, CURSOR_HEIGHT);
+        draw_sprite(buf, x, y, w, h, &self.sprite, Some(CURSOR_TRANSPARENT))
+    }
+    /// Restores the pixels that were under the cursor. Does nothing if it is not drawn.
+    pub fn erase<T: Bitmap + ?Sized>(&mut self, buf: &mut T) -> Result<()> {
+        let Some((x, y)) = self.position.take() else {
+            return Ok(());
+        };
+        draw_sprite(buf, x, y, CURSOR_WIDTH, CURSOR_HEIGHT, &self.saved, None)
+    }
+}
+impl Default for Cursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Cell size of the pattern drawn by draw_test_pattern().
+pub const TEST_PATTERN_CELL: i64 = 64;
+
+pub fn draw_test_pattern<T: Bitmap + ?Sized>(buf: &mut T) {
+    let cell = TEST_PATTERN_CELL;
+    draw_test_pattern_at(buf, buf.width() - 2 * cell - 1, 0, cell);
+}
+
+/// Height of the pattern that draw_test_pattern_at() draws with the same `cell`,
+/// e.g. to put a console right below it.
+pub fn test_pattern_height(cell: i64) -> i64 {
+    // 色のセル4段、文字2行、枠、円、BMP、グラデーションの帯の順に並ぶ
+    let cy = 4 * cell + 32 + 2 * cell + cell / 2;
+    cy + cell / 2 + 24 + cell / 4
+}
+
+/// Draws the test pattern with its top-left corner at (origin_x, origin_y). The color
+/// cells are `cell` pixels square and the rest of the pattern is scaled to match.
+/// Parts that don't fit in buf are skipped or clipped, so any size of buf is fine.
+pub fn draw_test_pattern_at<T: Bitmap + ?Sized>(
+    buf: &mut T,
+    origin_x: i64,
+    origin_y: i64,
+    cell: i64,
+) {
+    let left = origin_x;
+    let h = cell;
+    let w = 2 * cell;
+    let colors = [Color::BLACK, Color::RED, Color::GREEN, Color::BLUE];
+    for (i, c) in colors.iter().enumerate() {
+        let y = origin_y + i as i64 * h;
+        let _ = fill_rect(buf, *c, left, y, h, h);
+        let _ = fill_rect(buf, c.invert(), left + h, y, h, h);
+    }
+    let points = [(0, 0), (0, w), (w, 0), (w, w)];
+    for (x0, y0) in points.iter() {
+        for (x1, y1) in points.iter() {
+            let (y0, y1) = (origin_y + *y0, origin_y + *y1);
+            let _ = draw_line_thick(buf, 0xffffff, left + *x0, y0, left + *x1, y1, 2);
+        }
+    }
+    let text_top = origin_y + h * colors.len() as i64;
+    draw_str_fg(buf, left, text_top, 0x00ff00, "0123456789");
+    draw_str_fg(buf, left, text_top + 16, 0x00ff00, "ABCDEF");
+    let top = text_top + 32;
+    let _ = draw_rect_with_thickness(buf, 0x00ff00, left, top, w, w, max(cell / 16, 1));
+    for i in 1..4 {
+        let inset = i * cell / 4;
+        let size = w - 2 * inset;
+        let _ = draw_rect(buf, 0xffffff, left + inset, top + inset, size, size);
+    }
+    let cy = top + w + cell / 2;
+    let _ = fill_circle(buf, 0xff0000, left + w / 4, cy, cell * 3 / 8);
+    let _ = draw_circle(buf, 0x00ffff, left + w * 3 / 4, cy, cell * 3 / 8);
+    let _ = crate::bmp::draw_bmp(buf, left + w / 2 - 8, cy + cell / 2, TEST_PATTERN_BMP);
+    let bar_top = cy + cell / 2 + 24;
+    let (black, white) = (Color::BLACK, Color::WHITE);
+    let hz = GradientDirection::Horizontal;
+    let _ = fill_rect_gradient(buf, black, white, left, bar_top, w, cell / 4, hz);
+}
+
+// テストパターンに添える16x16の24bit BMP
+const TEST_PATTERN_BMP: &[u8] = include_bytes!("./logo.bmp");
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TEST_BITMAP_SIZE: i64 = 16;
+
+    struct TestBitmap {
+        buf: [u32; (TEST_BITMAP_SIZE * TEST_BITMAP_SIZE) as usize],
+    }
+    impl TestBitmap {
+        fn new() -> Self {
+            Self {
+                buf: [0; (TEST_BITMAP_SIZE * TEST_BITMAP_SIZE) as usize],
+            }
+        }
+        fn pixel(&self, x: i64, y: i64) -> u32 {
+            self.buf[(y * TEST_BITMAP_SIZE + x) as usize]
+        }
+    }
+    impl Bitmap for TestBitmap {
+        fn bytes_per_pixel(&self) -> i64 {
+            4
+        }
+        fn pixels_per_line(&self) -> i64 {
+            TEST_BITMAP_SIZE
+        }
+        fn width(&self) -> i64 {
+            TEST_BITMAP_SIZE
+        }
+        fn height(&self) -> i64 {
+            TEST_BITMAP_SIZE
+        }
+        fn buf(&self) -> *const u8 {
+            self.buf.as_ptr() as *const u8
+        }
+        fn buf_mut(&mut self) -> *mut u8 {
+            self.buf.as_mut_ptr() as *mut u8
+        }
+    }
+
+    const CANARY: u32 = 0xdeadbeef;
+    const CANARY_WIDTH: i64 = 12;
+    const CANARY_HEIGHT: i64 = 8;
+    const CANARY_PPL: i64 = 16;
+
+    // 描画可能な領域の前後の行と、行末の余白を番兵の値で埋めたBitmap
+    struct CanaryBitmap {
+        buf: [u32; (CANARY_PPL * (CANARY_HEIGHT + 2)) as usize],
+    }
+    impl CanaryBitmap {
+        fn new() -> Self {
+            let mut bmp = Self {
+                buf: [CANARY; (CANARY_PPL * (CANARY_HEIGHT + 2)) as usize],
+            };
+            for y in 1..=CANARY_HEIGHT {
+                for x in 0..CANARY_WIDTH {
+                    bmp.buf[(y * CANARY_PPL + x) as usize] = 0;
+                }
+            }
+            bmp
+        }
+        fn assert_canaries(&self) {
+            for (i, c) in self.buf.iter().enumerate() {
+                let (x, y) = (i as i64 % CANARY_PPL, i as i64 / CANARY_PPL);
+                if y == 0 || y > CANARY_HEIGHT || x >= CANARY_WIDTH {
+                    assert_eq!(*c, CANARY);
+                }
+            }
+        }
+    }
+    impl Bitmap for CanaryBitmap {
+        fn bytes_per_pixel(&self) -> i64 {
+            4
+        }
+        fn pixels_per_line(&self) -> i64 {
+            CANARY_PPL
+        }
+        fn width(&self) -> i64 {
+            CANARY_WIDTH
+        }
+        fn height(&self) -> i64 {
+            CANARY_HEIGHT
+        }
+        fn buf(&self) -> *const u8 {
+            unsafe { self.buf.as_ptr().add(CANARY_PPL as usize) as *const u8 }
+        }
+        fn buf_mut(&mut self) -> *mut u8 {
+            unsafe { self.buf.as_mut_ptr().add(CANARY_PPL as usize) as *mut u8 }
+        }
+    }
+
+    const EXTREME_VALUES: [i64; 12] = [
+        i64::MIN,
+        i64::MIN + 1,
+        i64::MIN / 2,
+        -CANARY_PPL,
+        -1,
+        0,
+        1,
+        CANARY_WIDTH - 1,
+        CANARY_WIDTH,
+        i64::MAX / 2,
+        i64::MAX - 1,
+        i64::MAX,
+    ];
+
+    #[test_case]
+    fn fill_rect_extreme_values() {
+        let mut bmp = CanaryBitmap::new();
+        for x in EXTREME_VALUES {
+            for y in EXTREME_VALUES {
+                for w in EXTREME_VALUES {
+                    for h in EXTREME_VALUES {
+                        let _ = fill_rect(&mut bmp, 0xffffff, x, y, w, h);
+                    }
+                }
+            }
+        }
+        bmp.assert_canaries();
+        assert!(fill_rect(&mut bmp, 0xffffff, 0, 0, CANARY_WIDTH, CANARY_HEIGHT).is_ok());
+        assert!(fill_rect(&mut bmp, 0xffffff, 1, 0, CANARY_WIDTH, CANARY_HEIGHT).is_err());
+        assert!(fill_rect(&mut bmp, 0xffffff, 1, 1, -1, 1).is_err());
+        bmp.assert_canaries();
+    }
+
+    #[test_case]
+    fn fill_rect_clipped_edges() {
+        let s = TEST_BITMAP_SIZE;
+        // 上下左右それぞれの辺からはみ出した矩形
+        for (px, py, visible) in [(-2, 4, 2), (s - 2, 4, 2), (4, -2, 2), (4, s - 2, 2)] {
+            let mut bmp = TestBitmap::new();
+            fill_rect_clipped(&mut bmp, 1, px, py, 4, 4).unwrap();
+            assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), visible * 4);
+        }
+        let mut bmp = TestBitmap::new();
+        fill_rect_clipped(&mut bmp, 1, -100, -100, 1000, 1000).unwrap();
+        assert!(bmp.buf.iter().all(|c| *c == 1));
+        let mut bmp = TestBitmap::new();
+        fill_rect_clipped(&mut bmp, 1, s, 0, 4, 4).unwrap();
+        fill_rect_clipped(&mut bmp, 1, -4, 0, 4, 4).unwrap();
+        fill_rect_clipped(&mut bmp, 1, 0, 0, -4, 4).unwrap();
+        assert!(bmp.buf.iter().all(|c| *c == 0));
+    }
+
+    #[test_case]
+    fn fill_rect_clipped_extreme_values() {
+        let mut bmp = CanaryBitmap::new();
+        for x in EXTREME_VALUES {
+            for y in EXTREME_VALUES {
+                for w in EXTREME_VALUES {
+                    for h in EXTREME_VALUES {
+                        fill_rect_clipped(&mut bmp, 0xffffff, x, y, w, h).unwrap();
+                    }
+                }
+            }
+        }
+        bmp.assert_canaries();
+    }
+
+    #[test_case]
+    fn draw_line_and_pixel_at_mut_extreme_values() {
+        let mut bmp = CanaryBitmap::new();
+        for x0 in EXTREME_VALUES {
+            for y0 in EXTREME_VALUES {
+                assert_eq!(
+                    bmp.pixel_at_mut(x0, y0).is_some(),
+                    (0..CANARY_WIDTH).contains(&x0) && (0..CANARY_HEIGHT).contains(&y0)
+                );
+                for x1 in EXTREME_VALUES {
+                    for y1 in EXTREME_VALUES {
+                        let _ = draw_line(&mut bmp, 0xffffff, x0, y0, x1, y1);
+                    }
+                }
+            }
+        }
+        bmp.assert_canaries();
+    }
+
+    #[test_case]
+    fn draw_str_fg_extreme_values() {
+        let mut bmp = CanaryBitmap::new();
+        for x in EXTREME_VALUES {
+            for y in EXTREME_VALUES {
+                draw_str_fg(&mut bmp, x, y, 0xffffff, "AB");
+            }
+        }
+        bmp.assert_canaries();
+    }
+
+    const K: u32 = 0xff00ff;
+    // 2x2のセルが2列x2行並んだシート
+    const SHEET: [u32; 16] = [
+        1, K, 3, 4, //
+        K, 2, 5, K, //
+        6, 6, K, K, //
+        6, 6, K, 7, //
+    ];
+
+    #[test_case]
+    fn draw_str_fg_non_ascii_keeps_columns() {
+        let mut expected = TestBitmap::new();
+        draw_str_fg(&mut expected, 0, 0, 0xffffff, "a");
+        draw_str_fg(&mut expected, 16, 0, 0xffffff, "b");
+        for (y, bits) in TOFU_GLYPH.iter().enumerate() {
+            for x in 0..8 {
+                if bits & (0x80 >> x) != 0 {
+                    *expected.pixel_at_mut(8 + x, y as i64).unwrap() = 0xffffff;
+                }
+            }
+        }
+        let mut actual = TestBitmap::new();
+        draw_str_fg(&mut actual, 0, 0, 0xffffff, "a\u{2026}b");
+        assert!(expected.buf == actual.buf);
+    }
+
+    // 描画したピクセルから、1行分のビット列を組み立てる
+    fn row_bits(bmp: &TestBitmap, y: i64) -> u8 {
+        (0..8).fold(0, |bits, x| bits | if bmp.pixel(x, y) != 0 { 0x80 >> x } else { 0 })
+    }
+
+    #[test_case]
+    fn font_rows_are_not_shifted() {
+        let mut bmp = TestBitmap::new();
+        draw_font_fg(&mut bmp, 0, 0, 1, 'A');
+        // 'A'の最初と最後の行は空で、点のある最初の行は...**...、最後の行は***..***
+        assert_eq!(row_bits(&bmp, 0), 0b0000_0000);
+        assert_eq!(row_bits(&bmp, 1), 0b0001_1000);
+        assert_eq!(row_bits(&bmp, 13), 0b1110_0111);
+        assert_eq!(row_bits(&bmp, 15), 0b0000_0000);
+        // グリフの間に空行がいくつあっても、ヘッダの直後の16行がグリフになる
+        let glyph = "0x41\n*.......\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n.......*\n";
+        let src = ["\n\n", glyph, "\n\n\n", glyph.replace("0x41", "0x42").as_str()].concat();
+        assert_eq!(font_source_error(src.as_bytes()), None);
+        let table = parse_font_table(src.as_bytes());
+        for c in [b'A', b'B'] {
+            assert_eq!(table[c as usize][0], 0x80);
+            assert_eq!(table[c as usize][15], 0x01);
+        }
+        assert_eq!(table[b'C' as usize], TOFU_GLYPH);
+    }
+
+    #[test_case]
+    fn lookup_font_missing_code_point() {
+        // font.txtに無いコードポイントは、Noneではなくとうふになる
+        let glyph = "0x41\n*.......\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n.......*\n";
+        let table = parse_font_table(glyph.as_bytes());
+        let tofu = glyph_chars(&table[b'C' as usize]);
+        assert_eq!(tofu[0], ['.'; 8]);
+        assert_eq!(tofu[1], ['.', '*', '*', '*', '*', '*', '*', '.']);
+        assert_eq!(tofu[2], ['.', '*', '.', '.', '.', '.', '*', '.']);
+        assert_eq!(glyph_chars(&table[b'A' as usize])[0][0], '*');
+        // 組み込みのfont.txtはU+00FFまで全部揃っているので、Noneになるのはその先だけ
+        for c in 0..=0xffu8 {
+            assert!(lookup_font(c as char).is_some());
+        }
+        assert_eq!(lookup_font('\u{100}'), None);
+    }
+
+    #[test_case]
+    fn font_table_matches_font_txt() {
+        let mut lines = FONT_SOURCE.split('\n').skip_while(|l| *l != "0x41").skip(1);
+        for bits in FONT_TABLE[b'A' as usize].iter() {
+            let line = lines.next().unwrap().as_bytes();
+            for (x, c) in line.iter().take(8).enumerate() {
+                assert_eq!(bits & (0x80 >> x) != 0, *c == b'*');
+            }
+        }
+        assert_eq!(lookup_font('A').unwrap()[15], ['.'; 8]);
+        assert!(FONT_TABLE[b'A' as usize] != TOFU_GLYPH);
+        assert_eq!(lookup_font('\u{100}'), None);
+        assert_eq!(glyph_rows('\u{100}'), &TOFU_GLYPH);
+    }
+
+    #[test_case]
+    fn font_table_golden_checksum() {
+        // font.txtや解析処理を変えたときに、意図せずグリフが変わっていないかを検出する。
+        // font.txtを意図して変更した場合は、この値も更新すること
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325; // FNV-1a
+        for bits in FONT_TABLE.iter().flatten() {
+            hash ^= *bits as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+        assert_eq!(hash, 0x6ad5_bf6a_e905_3ea1);
+    }
+
+    #[test_case]
+    fn font_error_message_has_line() {
+        let e = font_source_error(b"0x41\n**\n").unwrap();
+        let (buf, len) = font_error_message(e);
+        assert_eq!(
+            &buf[..len],
+            b"src/font.txt:2: glyph row must be exactly 8 characters"
+        );
+    }
+
+    #[test_case]
+    fn measure_str_and_chained_draw() {
+        assert_eq!(measure_str(""), 0);
+        assert_eq!(measure_str("AB"), 16);
+        assert_eq!(measure_str("a\u{2026}b"), 24);
+        assert_eq!(measure_str("A\nBCD\nEF"), 24);
+        let mut expected = TestBitmap::new();
+        draw_str_fg(&mut expected, 0, 0, 0xffffff, "AB");
+        let mut actual = TestBitmap::new();
+        let w = draw_str_fg(&mut actual, 0, 0, 0xffffff, "A");
+        assert_eq!(w, measure_str("A"));
+        draw_str_fg(&mut actual, w, 0, 0xffffff, "B");
+        assert!(expected.buf == actual.buf);
+    }
+
+    #[test_case]
+    fn draw_str_fg_bounded_clip_and_wrap() {
+        let mut expected = TestBitmap::new();
+        draw_str_fg(&mut expected, 0, 0, 0xffffff, "A");
+        let mut bmp = TestBitmap::new();
+        let n = draw_str_fg_bounded(&mut bmp, 0, 0, 0xffffff, "ABC", 15, TextOverflow::Clip);
+        assert_eq!(n, 1);
+        assert!(bmp.buf == expected.buf);
+        // 画面の右端でも切られる
+        let mut bmp = TestBitmap::new();
+        let n = draw_str_fg_bounded(&mut bmp, 0, 0, 0xffffff, "ABC", 100, TextOverflow::Clip);
+        assert_eq!(n, 2);
+
+        let mut expected = BitmapBuffer::new(16, 48);
+        draw_str_fg(&mut expected, 0, 0, 0xffffff, "AB");
+        draw_str_fg(&mut expected, 0, 16, 0xffffff, "C");
+        draw_str_fg(&mut expected, 0, 32, 0xffffff, "DE");
+        let mut bmp = BitmapBuffer::new(16, 48);
+        let n = draw_str_fg_bounded(&mut bmp, 0, 0, 0xffffff, "ABC\nDEF", 16, TextOverflow::Wrap);
+        assert_eq!(n, 5);
+        assert!(bmp.buf == expected.buf);
+        for (x, w) in [(0, 7), (i64::MAX, 8)] {
+            let n = draw_str_fg_bounded(&mut bmp, x, 0, 0xffffff, "AB", w, TextOverflow::Wrap);
+            assert_eq!(n, 0);
+        }
+    }
+
+    #[test_case]
+    fn draw_str_fg_with_psf_font() {
+        // 幅4x高さ2で、0x41('A')だけ左上と右下に点があるフォント
+        let mut glyphs = [0u8; 0x42 * 2];
+        glyphs[0x41 * 2] = 0b1000_0000;
+        glyphs[0x41 * 2 + 1] = 0b0001_0000;
+        let font = Font::new(&glyphs, 0x42, 2, 4, 2).unwrap();
+        let mut bmp = TestBitmap::new();
+        assert_eq!(draw_str_fg_with(&mut bmp, &font, 1, 1, 7, "AA"), 8);
+        assert_eq!(bmp.pixel(1, 1), 7);
+        assert_eq!(bmp.pixel(4, 2), 7);
+        assert_eq!(bmp.pixel(5, 1), 7);
+        assert_eq!(bmp.pixel(8, 2), 7);
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 4);
+        // 範囲外の文字も'?'もないフォントでは何も描かれない
+        let mut bmp = TestBitmap::new();
+        draw_str_fg_with(&mut bmp, &font, 0, 0, 7, "\u{3042}");
+        assert!(bmp.buf.iter().all(|c| *c == 0));
+    }
+
+    #[test_case]
+    fn proportional_text_width() {
+        assert_eq!(text_width_proportional(""), 0);
+        assert_eq!(
+            glyph_advance_proportional(' '),
+            PROPORTIONAL_SPACE_ADVANCE as i64
+        );
+        assert!(glyph_advance_proportional('i') < glyph_advance_proportional('W'));
+        assert_eq!(
+            glyph_advance_proportional('\u{2026}'),
+            6 + PROPORTIONAL_GLYPH_SPACING as i64
+        );
+        let mut bmp = TestBitmap::new();
+        let end = draw_str_proportional(&mut bmp, 0, 0, 0xffffff, "il");
+        assert_eq!(end, text_width_proportional("il"));
+        assert!(end < 16);
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in end..TEST_BITMAP_SIZE {
+                assert_eq!(bmp.pixel(x, y), 0);
+            }
+        }
+        assert!(bmp.buf.iter().any(|c| *c != 0));
+    }
+
+    // ピクセル列のFNV-1aハッシュ。描画結果の回帰テストに使う
+    fn pixels_checksum(bmp: &BitmapBuffer) -> u64 {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for p in bmp.buf.iter() {
+            for b in p.to_le_bytes() {
+                hash ^= b as u64;
+                hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+            }
+        }
+        hash
+    }
+
+    #[test_case]
+    fn proportional_punctuation_is_not_squeezed() {
+        for c in ['.', ',', ':', ';', '\'', '!'] {
+            assert_eq!(glyph_advance_proportional(c), 4);
+        }
+        // 1列だけの'!'は4pxの送り幅の中ほどに描かれる
+        let mut bmp = TestBitmap::new();
+        assert_eq!(draw_str_proportional(&mut bmp, 0, 0, 1, "!"), 4);
+        let used: Vec<i64> = (0..4)
+            .filter(|x| (0..16).any(|y| bmp.pixel(*x, y) != 0))
+            .collect();
+        assert_eq!(used, [1]);
+    }
+
+    #[test_case]
+    fn proportional_pangram_golden() {
+        const PANGRAM: &str = "The quick brown fox jumps over the lazy dog. 0123456789!";
+        let mut bmp = BitmapBuffer::new(400, 16);
+        let end = draw_str_proportional(&mut bmp, 0, 0, 0xffffff, PANGRAM);
+        assert_eq!(end, text_width_proportional(PANGRAM));
+        assert_eq!(end, 390);
+        assert_eq!(pixels_checksum(&bmp), 0x9c6d_ff97_bc0c_4cec);
+    }
+
+    #[test_case]
+    fn font_demo_golden() {
+        // 見出し(24px)と16x16のセル(16x24px)に、四辺4pxの余白を足した大きさ
+        const MARGIN: i64 = 4;
+        let (w, h) = (24 + 16 * 16 + MARGIN * 2, 24 + 16 * 24 + MARGIN * 2);
+        let mut bmp = BitmapBuffer::new(w, h);
+        draw_font_demo(&mut bmp, MARGIN, MARGIN, 0xffffff);
+        for y in 0..h {
+            for x in 0..w {
+                if x < MARGIN || y < MARGIN || x >= w - MARGIN || y >= h - MARGIN {
+                    assert_eq!(bmp.pixel_at(x, y), Some(0));
+                }
+            }
+        }
+        assert_eq!(pixels_checksum(&bmp), 0x110d_b4f4_8c2d_b3a5);
+    }
+
+    // 期待するパターンと1ピクセルずつ比べる。A→BとB→Aの両方向で描く
+    fn assert_line(x0: i64, y0: i64, x1: i64, y1: i64, expected: &[&str]) {
+        for (a, b) in [((x0, y0), (x1, y1)), ((x1, y1), (x0, y0))] {
+            let mut bmp = TestBitmap::new();
+            draw_line(&mut bmp, 1, a.0, a.1, b.0, b.1).unwrap();
+            for y in 0..TEST_BITMAP_SIZE {
+                for x in 0..TEST_BITMAP_SIZE {
+                    let on = expected
+                        .get(y as usize)
+                        .and_then(|row| row.as_bytes().get(x as usize))
+                        == Some(&b'*');
+                    assert_eq!(bmp.pixel(x, y), on as u32);
+                }
+            }
+        }
+    }
+
+    #[test_case]
+    fn draw_line_bresenham() {
+        assert_line(2, 1, 2, 1, &["", "..*"]);
+        assert_line(0, 0, 4, 0, &["*****"]);
+        assert_line(1, 0, 1, 3, &[".*", ".*", ".*", ".*"]);
+        assert_line(0, 0, 4, 2, &["**", "..**", "....*"]);
+        assert_line(0, 0, 1, 3, &["*", "*", ".*", ".*"]);
+        assert_line(0, 3, 3, 0, &["...*", "..*", ".*", "*"]);
+        assert_line(0, 2, 5, 0, &["....**", "..**", "**"]);
+        assert!(draw_line_unclipped(&mut TestBitmap::new(), 1, 0, 0, 16, 0).is_err());
+        let mut bmp = TestBitmap::new();
+        draw_line_unclipped(&mut bmp, 1, 0, 2, 5, 0).unwrap();
+        let mut clipped = TestBitmap::new();
+        draw_line(&mut clipped, 1, 0, 2, 5, 0).unwrap();
+        assert!(bmp.buf == clipped.buf);
+    }
+
+    // 切り詰めた線の点は、十分に大きいBitmapに描いた線の点のうち範囲内のものと一致する
+    fn assert_clipped_line(x0: i64, y0: i64, x1: i64, y1: i64) {
+        let mut large = BitmapBuffer::new(64, 64);
+        draw_line_unclipped(&mut large, 1, x0 + 24, y0 + 24, x1 + 24, y1 + 24).unwrap();
+        for (a, b) in [((x0, y0), (x1, y1)), ((x1, y1), (x0, y0))] {
+            let mut bmp = TestBitmap::new();
+            draw_line(&mut bmp, 1, a.0, a.1, b.0, b.1).unwrap();
+            for y in 0..TEST_BITMAP_SIZE {
+                for x in 0..TEST_BITMAP_SIZE {
+                    assert_eq!(bmp.pixel(x, y), large.pixel_at(x + 24, y + 24).unwrap());
+                }
+            }
+        }
+    }
+
+    #[test_case]
+    fn draw_line_clips_to_bitmap() {
+        // 2辺を横切る線
+        assert_clipped_line(-4, 8, 8, -4);
+        assert_clipped_line(-3, 2, 20, 14);
+        assert_clipped_line(-10, -20, 20, 30);
+        // 角を横切る縦線と横線
+        assert_clipped_line(0, -5, 0, 20);
+        assert_clipped_line(-5, 15, 20, 15);
+        assert_clipped_line(15, -8, 15, 3);
+        // 片方の端点だけが範囲外
+        assert_clipped_line(5, 5, 22, 9);
+        // 完全に上、下、左にある線は何も描かない
+        let outside = [
+            (-5, -1, 20, -1),
+            (0, 16, 15, 20),
+            (-8, 0, -1, 15),
+            (-8, 7, 7, -8),
+        ];
+        for (x0, y0, x1, y1) in outside {
+            let mut bmp = TestBitmap::new();
+            draw_line(&mut bmp, 1, x0, y0, x1, y1).unwrap();
+            assert!(bmp.buf.iter().all(|c| *c == 0));
+        }
+        // 桁あふれするような端点でも範囲内の部分が描かれる
+        let mut bmp = TestBitmap::new();
+        draw_line(&mut bmp, 1, i64::MIN, 3, i64::MAX, 3).unwrap();
+        assert!((0..TEST_BITMAP_SIZE).all(|x| bmp.pixel(x, 3) == 1));
+        let mut bmp = TestBitmap::new();
+        draw_line(&mut bmp, 1, i64::MIN, i64::MIN, i64::MAX, i64::MAX).unwrap();
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                assert_eq!(bmp.pixel(x, y), (x == y) as u32);
+            }
+        }
+    }
+
+    #[test_case]
+    fn draw_line_thick_matches_draw_line() {
+        let lines = [
+            (2, 1, 2, 1),
+            (0, 0, 15, 0),
+            (0, 0, 4, 2),
+            (0, 3, 3, 0),
+            (9, 9, 0, 15),
+        ];
+        for (x0, y0, x1, y1) in lines {
+            let mut expected = TestBitmap::new();
+            draw_line(&mut expected, 1, x0, y0, x1, y1).unwrap();
+            let mut actual = TestBitmap::new();
+            draw_line_thick(&mut actual, 1, x0, y0, x1, y1, 1).unwrap();
+            assert!(expected.buf == actual.buf);
+        }
+        let mut bmp = TestBitmap::new();
+        assert!(draw_line_thick(&mut bmp, 1, 0, 0, 4, 0, 0).is_err());
+    }
+
+    #[test_case]
+    fn draw_line_thick_clips_like_draw_line() {
+        // 片方の端点が画面外でも、大きなビットマップに描いたときと同じ点が描かれる
+        let lines = [
+            (2, 3, 40, 9),
+            (-5, 20, 12, -3),
+            (8, -30, 9, 30),
+            (-20, -20, -1, -1),
+            (17, 1, 17, 14),
+        ];
+        for (x0, y0, x1, y1) in lines {
+            for thickness in [1, 2, 3, 5] {
+                let mut large = BitmapBuffer::new(96, 96);
+                draw_line_thick(&mut large, 1, x0 + 40, y0 + 40, x1 + 40, y1 + 40, thickness)
+                    .unwrap();
+                let mut bmp = TestBitmap::new();
+                draw_line_thick(&mut bmp, 1, x0, y0, x1, y1, thickness).unwrap();
+                for y in 0..TEST_BITMAP_SIZE {
+                    for x in 0..TEST_BITMAP_SIZE {
+                        assert_eq!(bmp.pixel(x, y), large.pixel_at(x + 40, y + 40).unwrap());
+                    }
+                }
+            }
+        }
+        let mut bmp = CanaryBitmap::new();
+        for x0 in EXTREME_VALUES {
+            for y1 in EXTREME_VALUES {
+                draw_line_thick(&mut bmp, 1, x0, 3, 5, y1, 3).unwrap();
+                draw_line_thick(&mut bmp, 1, x0, y1, x0, 2, i64::MAX).unwrap();
+            }
+        }
+        bmp.assert_canaries();
+    }
+
+    #[test_case]
+    fn draw_line_thick_covers_corners() {
+        // 横線は縦方向に、縦線は横方向に太くなる
+        let mut bmp = TestBitmap::new();
+        draw_line_thick(&mut bmp, 1, 4, 4, 10, 4, 3).unwrap();
+        draw_line_thick(&mut bmp, 1, 10, 4, 10, 10, 3).unwrap();
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                let horizontal = (3..=11).contains(&x) && (3..=5).contains(&y);
+                let vertical = (9..=11).contains(&x) && (3..=11).contains(&y);
+                assert_eq!(bmp.pixel(x, y), (horizontal || vertical) as u32);
+            }
+        }
+        // 端の近くでははみ出した部分が切り取られる
+        let mut bmp = TestBitmap::new();
+        draw_line_thick(&mut bmp, 1, 0, 0, 15, 15, 4).unwrap();
+        assert_eq!(bmp.pixel(0, 0), 1);
+        assert_eq!(bmp.pixel(15, 15), 1);
+        assert_eq!(bmp.pixel(15, 0), 0);
+    }
+
+    #[test_case]
+    fn draw_rect_outline() {
+        let mut bmp = TestBitmap::new();
+        draw_rect(&mut bmp, 1, 2, 3, 4, 5).unwrap();
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                let on_edge = (x == 2 || x == 5) && (3..=7).contains(&y)
+                    || (y == 3 || y == 7) && (2..=5).contains(&x);
+                assert_eq!(bmp.pixel(x, y), on_edge as u32);
+            }
+        }
+        let mut bmp = TestBitmap::new();
+        draw_rect(&mut bmp, 1, 0, 0, 1, 1).unwrap();
+        draw_rect(&mut bmp, 1, 0, 2, 4, 1).unwrap();
+        draw_rect(&mut bmp, 1, 6, 0, 1, 3).unwrap();
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 1 + 4 + 3);
+        assert!(draw_rect(&mut bmp, 1, 0, 0, 0, 1).is_err());
+        assert!(draw_rect(&mut bmp, 1, 15, 0, 2, 1).is_err());
+    }
+
+    #[test_case]
+    fn draw_rect_with_thickness_grows_inward() {
+        let mut bmp = TestBitmap::new();
+        draw_rect_with_thickness(&mut bmp, 1, 0, 0, 6, 5, 2).unwrap();
+        // 2ピクセル幅の枠の内側に残るのは2x1だけ
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 6 * 5 - 2);
+        assert_eq!(bmp.pixel(1, 1), 1);
+        assert_eq!(bmp.pixel(2, 2), 0);
+        let mut bmp = TestBitmap::new();
+        draw_rect_with_thickness(&mut bmp, 1, 0, 0, 3, 3, 8).unwrap();
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 9);
+        assert!(draw_rect_with_thickness(&mut bmp, 1, 0, 0, 3, 3, 0).is_err());
+    }
+
+    #[test_case]
+    fn draw_circle_outline() {
+        let mut bmp = TestBitmap::new();
+        draw_circle(&mut bmp, 1, 8, 8, 0).unwrap();
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 1);
+        assert_eq!(bmp.pixel(8, 8), 1);
+        let mut bmp = TestBitmap::new();
+        draw_circle(&mut bmp, 1, 8, 8, 5).unwrap();
+        for (x, y) in [(13, 8), (3, 8), (8, 13), (8, 3)] {
+            assert_eq!(bmp.pixel(x, y), 1);
+        }
+        assert_eq!(bmp.pixel(8, 8), 0);
+        for y in 3..=13 {
+            for x in 3..=13 {
+                assert_eq!(bmp.pixel(x, y), bmp.pixel(16 - x, y));
+                assert_eq!(bmp.pixel(x, y), bmp.pixel(y, x));
+            }
+        }
+        assert!(draw_circle(&mut bmp, 1, 8, 8, -1).is_err());
+        assert!(draw_circle(&mut bmp, 1, 0, 0, i64::MAX).is_err());
+    }
+
+    #[test_case]
+    fn circles_are_clipped() {
+        let mut bmp = TestBitmap::new();
+        draw_circle(&mut bmp, 1, 8, 8, 9).unwrap();
+        draw_circle(&mut bmp, 1, -100, -100, 3).unwrap();
+        assert_eq!(bmp.pixel(8, 0), 0);
+        assert_eq!(bmp.pixel(0, 8), 0);
+        assert!(bmp.buf.iter().any(|c| *c != 0));
+        let mut bmp = TestBitmap::new();
+        fill_circle(&mut bmp, 1, 0, 0, 4).unwrap();
+        assert_eq!(bmp.pixel(0, 0), 1);
+        assert_eq!(bmp.pixel(4, 0), 1);
+        assert_eq!(bmp.pixel(3, 3), 1);
+        assert_eq!(bmp.pixel(4, 3), 0);
+        assert_eq!(bmp.pixel(5, 0), 0);
+        let mut bmp = CanaryBitmap::new();
+        for cx in EXTREME_VALUES {
+            for cy in EXTREME_VALUES {
+                let _ = draw_circle(&mut bmp, 1, cx, cy, 5);
+                let _ = fill_circle(&mut bmp, 1, cx, cy, 5);
+            }
+        }
+        bmp.assert_canaries();
+    }
+
+    #[test_case]
+    fn fill_circle_matches_outline() {
+        let mut outline = TestBitmap::new();
+        draw_circle(&mut outline, 1, 8, 8, 6).unwrap();
+        let mut disc = TestBitmap::new();
+        fill_circle(&mut disc, 1, 8, 8, 6).unwrap();
+        for (o, d) in outline.buf.iter().zip(disc.buf.iter()) {
+            assert!(*o == 0 || *d == 1);
+        }
+        let mut point = TestBitmap::new();
+        fill_circle(&mut point, 1, 8, 8, 0).unwrap();
+        assert_eq!(point.buf.iter().filter(|c| **c != 0).count(), 1);
+    }
+
+    #[test_case]
+    fn fill_triangle_spans() {
+        let mut bmp = TestBitmap::new();
+        fill_triangle(&mut bmp, 1, (0, 0), (4, 0), (0, 4)).unwrap();
+        // 上と左の辺の上の点は塗り、右下の斜辺の上の点は塗らない
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                assert_eq!(bmp.pixel(x, y), (x + y < 4) as u32);
+            }
+        }
+        // 頂点の順番によらず同じ結果になる
+        let p = [(2, 1), (13, 6), (5, 14)];
+        let mut expected = TestBitmap::new();
+        fill_triangle(&mut expected, 1, p[0], p[1], p[2]).unwrap();
+        for (a, b, c) in [(1, 0, 2), (2, 1, 0), (0, 2, 1)] {
+            let mut bmp = TestBitmap::new();
+            fill_triangle(&mut bmp, 1, p[a], p[b], p[c]).unwrap();
+            assert!(bmp.buf == expected.buf);
+        }
+        // 縮退した三角形は線になる
+        let mut bmp = TestBitmap::new();
+        fill_triangle(&mut bmp, 1, (1, 3), (7, 3), (4, 3)).unwrap();
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 7);
+        let mut bmp = TestBitmap::new();
+        fill_triangle(&mut bmp, 1, (0, 0), (2, 2), (4, 4)).unwrap();
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 5);
+        let mut bmp = TestBitmap::new();
+        fill_triangle(&mut bmp, 1, (5, 5), (5, 5), (5, 5)).unwrap();
+        assert_eq!(bmp.pixel(5, 5), 1);
+        // はみ出した部分は切り取られる
+        let mut bmp = TestBitmap::new();
+        fill_triangle(&mut bmp, 1, (-8, -8), (24, -8), (-8, 24)).unwrap();
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                assert_eq!(bmp.pixel(x, y), (x + y < 16) as u32);
+            }
+        }
+        let mut bmp = TestBitmap::new();
+        fill_triangle(&mut bmp, 1, (20, 0), (30, 0), (20, 10)).unwrap();
+        fill_triangle(&mut bmp, 1, (-20, -5), (-20, 30), (-21, 3)).unwrap();
+        assert!(bmp.buf.iter().all(|c| *c == 0));
+        assert!(fill_triangle(&mut bmp, 1, (0, 0), (4, 0), (0, i64::MAX)).is_err());
+        assert!(fill_triangle(&mut bmp, 1, (i64::MIN, 0), (4, 0), (0, 4)).is_err());
+    }
+
+    #[test_case]
+    fn fill_triangle_shared_edges() {
+        // 対角線で分けた正方形: 重なりも隙間もなく、右と下の辺は含まない
+        let mut upper = TestBitmap::new();
+        fill_triangle(&mut upper, 1, (0, 0), (10, 0), (10, 10)).unwrap();
+        let mut lower = TestBitmap::new();
+        fill_triangle(&mut lower, 1, (0, 0), (10, 10), (0, 10)).unwrap();
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                let count = upper.pixel(x, y) + lower.pixel(x, y);
+                assert_eq!(count, (x < 10 && y < 10) as u32);
+            }
+        }
+        // 斜めの辺を共有する扇形でも、共有する辺の上の点はちょうど1回だけ塗られる
+        let center = (8, 8);
+        let rim = [(2, 2), (14, 4), (12, 14), (2, 12), (2, 2)];
+        let mut count = [0u32; (TEST_BITMAP_SIZE * TEST_BITMAP_SIZE) as usize];
+        for pair in rim.windows(2) {
+            let mut bmp = TestBitmap::new();
+            fill_triangle(&mut bmp, 1, center, pair[0], pair[1]).unwrap();
+            for (c, p) in count.iter_mut().zip(bmp.buf.iter()) {
+                *c += p;
+            }
+        }
+        assert!(count.iter().all(|c| *c <= 1));
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                let on_shared_edge = rim[..4].iter().any(|r| {
+                    edge_function(center, *r, (x, y)) == 0
+                        && (min(center.0, r.0)..=max(center.0, r.0)).contains(&x)
+                        && (min(center.1, r.1)..=max(center.1, r.1)).contains(&y)
+                        && (x, y) != *r
+                });
+                if on_shared_edge {
+                    assert_eq!(count[(y * TEST_BITMAP_SIZE + x) as usize], 1);
+                }
+            }
+        }
+    }
+
+    #[test_case]
+    fn font_source_is_strict() {
+        assert_eq!(font_source_error(FONT_SOURCE.as_bytes()), None);
+        let glyph = "0x41\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n........\n";
+        assert_eq!(font_source_error(glyph.as_bytes()), None);
+        let short_row = glyph.replacen("........", ".......", 1);
+        assert_eq!(font_source_error(short_row.as_bytes()).map(|e| e.line), Some(2));
+        let stray = glyph.replacen("........", "...#....", 1);
+        assert_eq!(font_source_error(stray.as_bytes()).map(|e| e.line), Some(2));
+        let missing_row = glyph.replacen("........\n", "", 1);
+        assert_eq!(font_source_error(missing_row.as_bytes()).map(|e| e.line), Some(17));
+        let twice = [glyph, "\n", glyph].concat();
+        assert_eq!(font_source_error(twice.as_bytes()).map(|e| e.line), Some(19));
+    }
+
+    #[test_case]
+    fn blend_pixel_channels() {
+        assert_eq!(blend_pixel(0x123456, 0xabcdef, 255), 0xabcdef);
+        assert_eq!(blend_pixel(0x123456, 0xabcdef, 0), 0x123456);
+        assert_eq!(blend_pixel(0x000000, 0xff8000, 128), 0x804000);
+        assert_eq!(blend_pixel(0xffffff, 0x000000, 51), 0xcccccc);
+        // 3 * 128 / 255 = 1.505...
+        assert_eq!(blend_pixel(0x000000, 0x000003, 128), 0x000002);
+        assert_eq!(blend_pixel(0x000003, 0x000000, 128), 0x000001);
+    }
+
+    #[test_case]
+    fn blended_drawing() {
+        let mut bmp = TestBitmap::new();
+        fill_rect(&mut bmp, 0x0000ff, 0, 0, 4, 4).unwrap();
+        fill_rect_blended(&mut bmp, 0xff0000, 0, 0, 0, 4, 4).unwrap();
+        assert_eq!(bmp.pixel(0, 0), 0x0000ff);
+        fill_rect_blended(&mut bmp, 0xff0000, 128, 1, 1, 2, 2).unwrap();
+        assert_eq!(bmp.pixel(1, 1), 0x80007f);
+        assert_eq!(bmp.pixel(0, 0), 0x0000ff);
+        assert_eq!(bmp.pixel_at(2, 2), Some(0x80007f));
+        fill_rect_blended(&mut bmp, 0xff0000, 255, 0, 0, 4, 4).unwrap();
+        assert_eq!(bmp.pixel(3, 3), 0xff0000);
+        assert!(fill_rect_blended(&mut bmp, 0, 0, 15, 15, 2, 2).is_err());
+        assert_eq!(bmp.pixel_at(16, 0), None);
+        draw_point_blended(&mut bmp, 0x0000ff, 128, 3, 3).unwrap();
+        assert_eq!(bmp.pixel(3, 3), 0x7f0080);
+        assert!(draw_point_blended(&mut bmp, 0x0000ff, 128, 16, 0).is_err());
+    }
+
+    #[test_case]
+    fn transfer_rect_clips_both_sides() {
+        let mut src = TestBitmap::new();
+        for (i, c) in src.buf.iter_mut().enumerate() {
+            *c = i as u32;
+        }
+        let mut dst = CanaryBitmap::new();
+        transfer_rect(&mut dst, &src, 2, 3, 1, 1, 4, 2).unwrap();
+        assert_eq!(dst.pixel_at(1, 1), Some(3 * 16 + 2));
+        assert_eq!(dst.pixel_at(4, 2), Some(4 * 16 + 5));
+        assert_eq!(dst.pixel_at(5, 2), Some(0));
+        // コピー元とコピー先の両方からはみ出す
+        let mut dst = CanaryBitmap::new();
+        transfer_rect(&mut dst, &src, -1, 14, 10, 6, 8, 8).unwrap();
+        assert_eq!(dst.pixel_at(11, 6), Some(14 * 16));
+        assert_eq!(dst.pixel_at(10, 6), Some(0));
+        assert_eq!(dst.pixel_at(11, 7), Some(15 * 16));
+        dst.assert_canaries();
+        let mut dst = CanaryBitmap::new();
+        for v in EXTREME_VALUES {
+            transfer_rect(&mut dst, &src, v, v, v.saturating_neg(), v, i64::MAX, i64::MAX).unwrap();
+            transfer_rect(&mut dst, &src, 0, 0, v, v.saturating_neg(), 16, 16).unwrap();
+        }
+        dst.assert_canaries();
+        assert!(transfer_rect(&mut dst, &src, 0, 0, 0, 0, -1, 1).is_err());
+    }
+
+    #[test_case]
+    fn blit_sprite_onto_bitmap() {
+        let mut sprite = TestBitmap::new();
+        fill_rect(&mut sprite, 0x00ff00, 0, 0, 4, 4).unwrap();
+        let mut dst = CanaryBitmap::new();
+        blit(&mut dst, 10, 6, &sprite, 0, 0, 4, 4).unwrap();
+        assert_eq!(dst.pixel_at(11, 7), Some(0x00ff00));
+        assert_eq!(dst.pixel_at(9, 6), Some(0));
+        blit(&mut dst, 0, 0, &sprite, 0, 0, 0, 4).unwrap();
+        blit(&mut dst, 0, 0, &sprite, 0, 0, 4, 0).unwrap();
+        assert_eq!(dst.pixel_at(0, 0), Some(0));
+        dst.assert_canaries();
+    }
+
+    #[test_case]
+    fn transfer_rotated_places_glyph() {
+        let mut src = TestBitmap::new();
+        draw_font_fg(&mut src, 4, 0, 0xffffff, 'F');
+        src.buf[(2 * TEST_BITMAP_SIZE + 1) as usize] = 0x123456;
+        let s = TEST_BITMAP_SIZE;
+        for (rotation, (x, y)) in [
+            (Rotation::Rotate0, (1, 2)),
+            (Rotation::Rotate90, (s - 1 - 2, 1)),
+            (Rotation::Rotate180, (s - 1 - 1, s - 1 - 2)),
+            (Rotation::Rotate270, (2, s - 1 - 1)),
+        ] {
+            let mut dst = TestBitmap::new();
+            transfer_rotated(&mut dst, &src, rotation).unwrap();
+            assert_eq!(dst.pixel(x, y), 0x123456);
+            for sy in 0..s {
+                for sx in 0..s {
+                    let (dx, dy) = rotation.map(sx, sy, s, s);
+                    assert_eq!(dst.pixel(dx, dy), src.pixel(sx, sy));
+                }
+            }
+            // 行末に余白のあるBitmapへの回転では、はみ出した部分が切り取られる
+            let mut padded = CanaryBitmap::new();
+            transfer_rotated(&mut padded, &src, rotation).unwrap();
+            padded.assert_canaries();
+        }
+        assert_eq!(Rotation::Rotate90.rotated_size(640, 480), (480, 640));
+    }
+
+    const ROTATIONS: [Rotation; 4] = [
+        Rotation::Rotate0,
+        Rotation::Rotate90,
+        Rotation::Rotate180,
+        Rotation::Rotate270,
+    ];
+
+    // 行単位の高速化をしている関数を一通り使う。7x7に収まるので回転後の大きさでも描ける
+    fn draw_rotation_scene<T: Bitmap + ?Sized>(buf: &mut T) {
+        let (w, h) = (buf.width(), buf.height());
+        fill_rect(buf, 0x102030, 0, 0, w, h).unwrap();
+        let g = GradientDirection::Horizontal;
+        fill_rect_gradient(buf, 0x000000, 0x0000ff, 1, 1, 5, 3, g).unwrap();
+        draw_line(buf, 0xffffff, 2, 0, 6, 6).unwrap();
+        let mut sprite = BitmapBuffer::new(3, 2);
+        for (i, p) in sprite.buf.iter_mut().enumerate() {
+            *p = 0x010101 * i as u32;
+        }
+        blit(buf, 0, 4, &sprite, 0, 0, 3, 2).unwrap();
+        let sheet = SpriteSheet::new(&SHEET, 4, 4, 2, 2).unwrap().with_color_key(K);
+        sheet.draw_cell(buf, 4, 4, 1, 0).unwrap();
+        flip_region_v(buf, 0, 0, w, 4).unwrap();
+        flip_region_h(buf, 0, 0, 5, h).unwrap();
+        scroll_area(buf, 0, 0, w, h, 1, 0x00ff00).unwrap();
+    }
+
+    #[test_case]
+    fn rotated_bitmap_maps_drawing() {
+        for rotation in ROTATIONS {
+            let mut rotated = RotatedBitmap::new(BitmapBuffer::new(12, 7), rotation);
+            let (w, h) = rotation.rotated_size(12, 7);
+            assert_eq!((rotated.width(), rotated.height()), (w, h));
+            assert_eq!(rotated.clip_bounds(), (0, 0, w, h));
+            assert_eq!(rotated.has_contiguous_rows(), rotation == Rotation::Rotate0);
+            draw_rotation_scene(&mut rotated);
+            // 回転していない画像に描いてから回転させたものと一致する
+            let mut upright = BitmapBuffer::new(w, h);
+            draw_rotation_scene(&mut upright);
+            let mut expected = BitmapBuffer::new(12, 7);
+            transfer_rotated(&mut expected, &upright, rotation).unwrap();
+            assert!(rotated.inner().buf == expected.buf);
+            for y in 0..h {
+                for x in 0..w {
+                    assert_eq!(rotated.pixel_at(x, y), upright.pixel_at(x, y));
+                }
+            }
+            assert_eq!(rotated.pixel_at(w, 0), None);
+            assert_eq!(rotated.pixel_at(0, -1), None);
+        }
+    }
+
+    #[test_case]
+    fn rotated_bitmap_regions_and_padding() {
+        for rotation in ROTATIONS {
+            // 行末に余白のあるBitmapを回転させても余白には書かない
+            let mut rotated = RotatedBitmap::new(CanaryBitmap::new(), rotation);
+            let (w, h) = (rotated.width(), rotated.height());
+            fill_rect(&mut rotated, 0xffffff, 0, 0, w, h).unwrap();
+            draw_rotation_scene(&mut rotated);
+            let mut region = BitmapRegion::new(&mut rotated, 1, 2, 3, 4).unwrap();
+            fill_rect(&mut region, 0x123456, 0, 0, 3, 4).unwrap();
+            scroll_up(&mut region, 1, 0x654321).unwrap();
+            assert_eq!(rotated.pixel_at(1, 2), Some(0x123456));
+            assert_eq!(rotated.pixel_at(3, 5), Some(0x654321));
+            assert_eq!(rotated.pixel_at(4, 5), upright_scene_pixel(w, h, 4, 5));
+            rotated.into_inner().assert_canaries();
+        }
+        // 描画範囲を狭めたBitmapを回転させると、描画範囲も回転する
+        let mut inner = BitmapBuffer::new(12, 7);
+        let clip = Rect::new(2, 1, 4, 3);
+        let rotated = RotatedBitmap::new(Clipped::new(&mut inner, clip), Rotation::Rotate90);
+        assert_eq!(rotated.clip_bounds(), (1, 6, 4, 10));
+    }
+
+    fn upright_scene_pixel(w: i64, h: i64, x: i64, y: i64) -> Option<u32> {
+        let mut upright = BitmapBuffer::new(w, h);
+        draw_rotation_scene(&mut upright);
+        upright.pixel_at(x, y)
+    }
+
+    #[test_case]
+    fn bitmap_buffer_flush() {
+        let mut back = BitmapBuffer::new(CANARY_WIDTH + 3, 5);
+        assert_eq!(back.pixels_per_line(), CANARY_WIDTH + 3);
+        fill_rect(&mut back, 0x0000ff, 0, 0, CANARY_WIDTH + 3, 5).unwrap();
+        draw_line(&mut back, 0xffffff, 0, 4, CANARY_WIDTH + 2, 4).unwrap();
+        let mut vram = CanaryBitmap::new();
+        back.flush_to(&mut vram).unwrap();
+        assert_eq!(vram.pixel_at(CANARY_WIDTH - 1, 0), Some(0x0000ff));
+        assert_eq!(vram.pixel_at(0, 4), Some(0xffffff));
+        assert_eq!(vram.pixel_at(0, 5), Some(0));
+        vram.assert_canaries();
+        let empty = BitmapBuffer::new(-1, 10);
+        assert_eq!(empty.width(), 0);
+        empty.flush_to(&mut vram).unwrap();
+    }
+
+    #[test_case]
+    fn back_buffer_present() {
+        let mut vram = CanaryBitmap::new();
+        let mut back = BackBuffer::new_like(&vram);
+        assert_eq!(back.pixels_per_line(), CANARY_PPL);
+        fill_rect(&mut back, 0x00ff00, 0, 0, CANARY_WIDTH, CANARY_HEIGHT).unwrap();
+        // 行末の余白にあたる部分も番兵の値にしておく
+        for y in 0..CANARY_HEIGHT {
+            for x in CANARY_WIDTH..CANARY_PPL {
+                back.buf[(y * CANARY_PPL + x) as usize] = CANARY;
+            }
+        }
+        present(&back, &mut vram).unwrap();
+        assert_eq!(vram.pixel_at(CANARY_WIDTH - 1, CANARY_HEIGHT - 1), Some(0x00ff00));
+        vram.assert_canaries();
+        let small = BackBuffer::new(CANARY_WIDTH, CANARY_HEIGHT);
+        assert!(present(&small, &mut vram).is_err());
+    }
+
+    #[test_case]
+    fn back_buffer_present_to_region() {
+        let mut vram = CanaryBitmap::new();
+        {
+            let mut region = BitmapRegion::new(&mut vram, 2, 1, 6, 3).unwrap();
+            let mut back = BackBuffer::new_like(&region);
+            assert_eq!(back.pixels_per_line(), CANARY_PPL);
+            fill_rect(&mut back, 0x00ff00, 0, 0, 6, 3).unwrap();
+            // 余白に書いた値はregionの外(親の画素)にあたるので、コピーされてはいけない
+            for y in 0..3 {
+                for x in 6..CANARY_PPL {
+                    back.buf[(y * CANARY_PPL + x) as usize] = 0xff0000;
+                }
+            }
+            present(&back, &mut region).unwrap();
+        }
+        for y in 0..CANARY_HEIGHT {
+            for x in 0..CANARY_WIDTH {
+                let inside = (2..8).contains(&x) && (1..4).contains(&y);
+                let expected = if inside { 0x00ff00 } else { 0 };
+                assert_eq!(vram.pixel_at(x, y), Some(expected));
+            }
+        }
+        vram.assert_canaries();
+    }
+
+    #[test_case]
+    fn draw_char_paints_whole_cell() {
+        let mut bmp = TestBitmap::new();
+        draw_str_fg(&mut bmp, 0, 0, 0xffffff, "AB");
+        draw_str(&mut bmp, 0, 0, 0x00ff00, 0x000080, "IJ");
+        let mut expected = TestBitmap::new();
+        fill_rect(&mut expected, 0x000080, 0, 0, 16, 16).unwrap();
+        draw_str_fg(&mut expected, 0, 0, 0x00ff00, "IJ");
+        assert!(bmp.buf == expected.buf);
+        let mut fallback = TestBitmap::new();
+        draw_char(&mut fallback, 0, 0, 1, 2, '\u{3042}');
+        assert!(fallback.buf.iter().take(8).all(|c| *c != 0));
+    }
+
+    #[test_case]
+    fn draw_str_bg_matches_draw_str() {
+        let mut expected = TestBitmap::new();
+        draw_str(&mut expected, 3, 1, 0x00ff00, 0x000080, "A\u{3042}");
+        let mut actual = TestBitmap::new();
+        draw_str_fg(&mut actual, 3, 1, 0xffffff, "XY");
+        draw_str_bg(&mut actual, 3, 1, 0x00ff00, 0x000080, "A\u{3042}");
+        assert!(expected.buf == actual.buf);
+        let mut bmp = CanaryBitmap::new();
+        for x in EXTREME_VALUES {
+            for y in EXTREME_VALUES {
+                draw_str_bg(&mut bmp, x, y, 0xffffff, 0x000080, "AB");
+            }
+        }
+        bmp.assert_canaries();
+    }
+
+    #[test_case]
+    fn draw_font_scaled_replicates_pixels() {
+        let mut expected = TestBitmap::new();
+        draw_str_fg(&mut expected, 1, 0, 0xffffff, "Hi");
+        for scale in [1, 0, -3] {
+            let mut actual = TestBitmap::new();
+            draw_str_fg_scaled(&mut actual, 1, 0, 0xffffff, "Hi", scale);
+            assert!(expected.buf == actual.buf);
+        }
+        let mut small = TestBitmap::new();
+        draw_font_fg(&mut small, 0, 0, 0xffffff, 'H');
+        let mut large = TestBitmap::new();
+        draw_font_scaled(&mut large, 0, 0, 0xffffff, 'H', 2);
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                assert_eq!(large.pixel(x, y), small.pixel(x / 2, y / 2));
+            }
+        }
+        let mut bmp = CanaryBitmap::new();
+        for v in EXTREME_VALUES {
+            draw_str_fg_scaled(&mut bmp, v, v, 0xffffff, "AB", v);
+        }
+        bmp.assert_canaries();
+    }
+
+    struct HugeBitmap;
+    impl Bitmap for HugeBitmap {
+        fn bytes_per_pixel(&self) -> i64 {
+            4
+        }
+        fn pixels_per_line(&self) -> i64 {
+            1 << 20
+        }
+        fn width(&self) -> i64 {
+            1 << 20
+        }
+        fn height(&self) -> i64 {
+            1 << 20
+        }
+        fn buf(&self) -> *const u8 {
+            core::ptr::null()
+        }
+        fn buf_mut(&mut self) -> *mut u8 {
+            core::ptr::null_mut()
+        }
+    }
+
+    #[test_case]
+    fn back_buffer_try_new_like() {
+        // 4TiB分のバッファは確保できないので、abortせずにErrになる
+        assert!(BitmapBuffer::try_new_like(&HugeBitmap).is_err());
+        let src = TestBitmap::new();
+        let back = BitmapBuffer::try_new_like(&src).unwrap();
+        assert_eq!(back.width(), TEST_BITMAP_SIZE);
+        assert_eq!(back.height(), TEST_BITMAP_SIZE);
+        assert_eq!(back.pixel_at(15, 15), Some(0));
+    }
+
+    #[test_case]
+    fn flip_region() {
+        let mut bmp = TestBitmap::new();
+        for y in 0..3 {
+            for x in 0..3 {
+                *bmp.pixel_at_mut(x + 1, y + 1).unwrap() = (y * 3 + x) as u32 + 1;
+            }
+        }
+        flip_region_h(&mut bmp, 1, 1, 3, 3).unwrap();
+        assert_eq!(bmp.pixel(1, 1), 3);
+        assert_eq!(bmp.pixel(2, 1), 2);
+        assert_eq!(bmp.pixel(3, 1), 1);
+        assert_eq!(bmp.pixel(0, 1), 0);
+        flip_region_v(&mut bmp, 1, 1, 3, 3).unwrap();
+        assert_eq!(bmp.pixel(1, 1), 9);
+        assert_eq!(bmp.pixel(1, 2), 6);
+        assert_eq!(bmp.pixel(3, 3), 1);
+        assert_eq!(bmp.pixel(1, 0), 0);
+        // 2回反転すると元に戻る
+        let before = bmp.buf;
+        flip_region_h(&mut bmp, 0, 0, 16, 16).unwrap();
+        flip_region_h(&mut bmp, 0, 0, 16, 16).unwrap();
+        flip_region_v(&mut bmp, 0, 0, 16, 16).unwrap();
+        flip_region_v(&mut bmp, 0, 0, 16, 16).unwrap();
+        assert!(before == bmp.buf);
+        assert!(flip_region_h(&mut bmp, 15, 0, 2, 1).is_err());
+        assert!(flip_region_v(&mut bmp, 0, 15, 1, 2).is_err());
+        assert!(flip_region_v(&mut bmp, 0, 0, -1, 2).is_err());
+    }
+
+    #[test_case]
+    fn scroll_area_moves_rows() {
+        let fill_pattern = |bmp: &mut TestBitmap| {
+            for y in 0..TEST_BITMAP_SIZE {
+                for x in 0..TEST_BITMAP_SIZE {
+                    *bmp.pixel_at_mut(x, y).unwrap() = (y * TEST_BITMAP_SIZE + x) as u32;
+                }
+            }
+        };
+        let original = |x: i64, y: i64| (y * TEST_BITMAP_SIZE + x) as u32;
+        let in_rect = |x, y| (2..7).contains(&x) && (3..9).contains(&y);
+        // 上に2行ずらすと、下の2行が塗りつぶされる
+        let mut bmp = TestBitmap::new();
+        fill_pattern(&mut bmp);
+        scroll_area(&mut bmp, 2, 3, 5, 6, 2, 0xffffff).unwrap();
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                let expected = match (in_rect(x, y), y) {
+                    (false, _) => original(x, y),
+                    (true, 7..) => 0xffffff,
+                    (true, _) => original(x, y + 2),
+                };
+                assert_eq!(bmp.pixel(x, y), expected);
+            }
+        }
+        // 負の値では下にずれ、上の行が塗りつぶされる
+        let mut bmp = TestBitmap::new();
+        fill_pattern(&mut bmp);
+        scroll_area(&mut bmp, 2, 3, 5, 6, -4, 0xffffff).unwrap();
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                let expected = match (in_rect(x, y), y) {
+                    (false, _) => original(x, y),
+                    (true, ..=6) => 0xffffff,
+                    (true, _) => original(x, y - 4),
+                };
+                assert_eq!(bmp.pixel(x, y), expected);
+            }
+        }
+        // 高さ以上ずらすと全体が塗りつぶされるだけになる
+        for dy in [6, -6, i64::MAX, i64::MIN] {
+            let mut bmp = TestBitmap::new();
+            fill_pattern(&mut bmp);
+            scroll_area(&mut bmp, 2, 3, 5, 6, dy, 0xffffff).unwrap();
+            for y in 0..TEST_BITMAP_SIZE {
+                for x in 0..TEST_BITMAP_SIZE {
+                    let expected = if in_rect(x, y) { 0xffffff } else { original(x, y) };
+                    assert_eq!(bmp.pixel(x, y), expected);
+                }
+            }
+        }
+        let mut bmp = TestBitmap::new();
+        scroll_area(&mut bmp, 0, 0, 16, 16, 0, 1).unwrap();
+        assert!(bmp.buf.iter().all(|c| *c == 0));
+        assert!(scroll_area(&mut bmp, 0, 10, 16, 7, 1, 1).is_err());
+        assert!(scroll_area(&mut bmp, 0, 0, -1, 2, 1, 1).is_err());
+        assert!(bmp.buf.iter().all(|c| *c == 0));
+    }
+
+    #[test_case]
+    fn scroll_up_whole_bitmap() {
+        let mut bmp = TestBitmap::new();
+        for (i, c) in bmp.buf.iter_mut().enumerate() {
+            *c = i as u32;
+        }
+        scroll_up(&mut bmp, 3, 0xffffff).unwrap();
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                let moved = ((y + 3) * TEST_BITMAP_SIZE + x) as u32;
+                let expected = if y < 13 { moved } else { 0xffffff };
+                assert_eq!(bmp.pixel(x, y), expected);
+            }
+        }
+        scroll_up(&mut bmp, 0, 1).unwrap();
+        assert_eq!(bmp.pixel(0, 0), 3 * TEST_BITMAP_SIZE as u32);
+        assert!(scroll_up(&mut bmp, -1, 1).is_err());
+        for rows in [16, i64::MAX] {
+            scroll_up(&mut bmp, rows, rows as u32 & 0xff).unwrap();
+            assert!(bmp.buf.iter().all(|c| *c == rows as u32 & 0xff));
+        }
+        // 行末に余白があっても、描画可能な範囲だけをずらす
+        let mut bmp = CanaryBitmap::new();
+        fill_rect(&mut bmp, 1, 0, 4, CANARY_WIDTH, 1).unwrap();
+        scroll_up(&mut bmp, 2, 2).unwrap();
+        assert_eq!(bmp.pixel_at(CANARY_WIDTH - 1, 2), Some(1));
+        assert_eq!(bmp.pixel_at(0, 4), Some(0));
+        assert_eq!(bmp.pixel_at(0, CANARY_HEIGHT - 1), Some(2));
+        bmp.assert_canaries();
+    }
+
+    #[test_case]
+    fn fill_rect_gradient_rows() {
+        let mut bmp = TestBitmap::new();
+        let v = GradientDirection::Vertical;
+        fill_rect_gradient(&mut bmp, 0x00ff00, 0xff0000, 1, 2, 3, 4, v).unwrap();
+        assert_eq!(bmp.pixel(1, 2), 0x00ff00);
+        assert_eq!(bmp.pixel(3, 2), 0x00ff00);
+        // 255 / 3 = 85
+        assert_eq!(bmp.pixel(2, 3), 0x55aa00);
+        assert_eq!(bmp.pixel(2, 4), 0xaa5500);
+        assert_eq!(bmp.pixel(3, 5), 0xff0000);
+        assert_eq!(bmp.pixel(0, 2), 0);
+        assert_eq!(bmp.pixel(1, 6), 0);
+        // 1/2の位置は四捨五入される: 0x01 + (0x02 - 0x01) / 2 = 1.5
+        fill_rect_gradient(&mut bmp, 0x000001, 0x000002, 0, 0, 1, 3, v).unwrap();
+        assert_eq!(bmp.pixel(0, 1), 0x000002);
+        fill_rect_gradient(&mut bmp, 0x123456, 0xffffff, 0, 0, 2, 1, v).unwrap();
+        assert_eq!(bmp.pixel(1, 0), 0x123456);
+        assert!(fill_rect_gradient(&mut bmp, 0, 0, 15, 0, 2, 1, v).is_err());
+        assert!(fill_rect_gradient(&mut bmp, 0, 0, 0, 0, 1, -1, v).is_err());
+    }
+
+    #[test_case]
+    fn fill_rect_gradient_columns() {
+        let mut bmp = TestBitmap::new();
+        let hz = GradientDirection::Horizontal;
+        fill_rect_gradient(&mut bmp, 0x00ff00, 0xff0000, 2, 1, 4, 3, hz).unwrap();
+        for y in 1..4 {
+            assert_eq!(bmp.pixel(2, y), 0x00ff00);
+            assert_eq!(bmp.pixel(3, y), 0x55aa00);
+            assert_eq!(bmp.pixel(4, y), 0xaa5500);
+            assert_eq!(bmp.pixel(5, y), 0xff0000);
+            assert_eq!(bmp.pixel(1, y), 0);
+            assert_eq!(bmp.pixel(6, y), 0);
+        }
+        assert_eq!(bmp.pixel(2, 0), 0);
+        assert_eq!(bmp.pixel(2, 4), 0);
+        // 幅が1なら最初の色で塗るだけ
+        fill_rect_gradient(&mut bmp, 0x123456, 0xffffff, 0, 8, 1, 2, hz).unwrap();
+        assert_eq!(bmp.pixel(0, 9), 0x123456);
+        assert!(fill_rect_gradient(&mut bmp, 0, 0, 14, 0, 3, 1, hz).is_err());
+        assert!(fill_rect_gradient(&mut bmp, 0, 0, 0, 15, 1, 2, hz).is_err());
+    }
+
+    #[test_case]
+    fn bitmap_region_clips_and_nests() {
+        let mut bmp = CanaryBitmap::new();
+        {
+            let mut region = BitmapRegion::new(&mut bmp, 2, 1, 6, 4).unwrap();
+            assert_eq!((region.width(), region.height()), (6, 4));
+            fill_rect(&mut region, 1, 0, 0, 6, 4).unwrap();
+            assert!(fill_rect(&mut region, 1, 0, 0, 7, 1).is_err());
+            assert_eq!(region.pixel_at(6, 0), None);
+            assert_eq!(region.pixel_at(0, -1), None);
+            for x in EXTREME_VALUES {
+                for y in EXTREME_VALUES {
+                    draw_str_fg(&mut region, x, y, 2, "AB");
+                    let _ = draw_line(&mut region, 2, x, y, 3, 3);
+                }
+            }
+            let mut inner = BitmapRegion::new(&mut region, 1, 1, 2, 2).unwrap();
+            fill_rect(&mut inner, 3, 0, 0, 2, 2).unwrap();
+            assert!(BitmapRegion::new(&mut inner, 1, 1, 2, 1).is_err());
+        }
+        for y in 0..CANARY_HEIGHT {
+            for x in 0..CANARY_WIDTH {
+                let inside = (2..8).contains(&x) && (1..5).contains(&y);
+                let p = bmp.pixel_at(x, y).unwrap();
+                assert_eq!(p != 0, inside);
+                if (3..5).contains(&x) && (2..4).contains(&y) {
+                    assert_eq!(p, 3);
+                }
+            }
+        }
+        bmp.assert_canaries();
+        assert!(BitmapRegion::new(&mut bmp, CANARY_WIDTH - 1, 0, 2, 1).is_err());
+    }
+
+    #[test_case]
+    fn draw_image_raw_clips() {
+        let img = [1, 2, 3, 4, 5, 6];
+        let mut bmp = TestBitmap::new();
+        draw_image_raw(&mut bmp, 2, 3, &img, 3, 2).unwrap();
+        assert_eq!([2, 3, 4].map(|x| bmp.pixel(x, 3)), [1, 2, 3]);
+        assert_eq!([2, 3, 4].map(|x| bmp.pixel(x, 4)), [4, 5, 6]);
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 6);
+        // はみ出した部分は切り取られる
+        let mut bmp = TestBitmap::new();
+        draw_image_raw(&mut bmp, 14, -1, &img, 3, 2).unwrap();
+        assert_eq!(bmp.pixel(14, 0), 4);
+        assert_eq!(bmp.pixel(15, 0), 5);
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 2);
+        draw_image_raw(&mut bmp, i64::MIN, i64::MAX, &img, 3, 2).unwrap();
+        draw_image_raw(&mut bmp, 0, 0, &[], 0, 5).unwrap();
+        // 大きさが合わない場合は何も描かない
+        assert!(draw_image_raw(&mut bmp, 0, 0, &img, 2, 2).is_err());
+        assert!(draw_image_raw(&mut bmp, 0, 0, &img, 7, 1).is_err());
+        assert!(draw_image_raw(&mut bmp, 0, 0, &img, -3, -2).is_err());
+        assert!(draw_image_raw(&mut bmp, 0, 0, &img, i64::MAX, 2).is_err());
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 2);
+    }
+
+    #[test_case]
+    fn cursor_restores_background() {
+        let mut original = TestBitmap::new();
+        for (i, c) in original.buf.iter_mut().enumerate() {
+            *c = i as u32;
+        }
+        let mut bmp = TestBitmap { buf: original.buf };
+        let mut cursor = Cursor::new();
+        cursor.draw(&mut bmp, 3, 2).unwrap();
+        assert_eq!(cursor.position(), Some((3, 2)));
+        assert_eq!(bmp.pixel(3, 2), Color::BLACK.to_u32());
+        assert_eq!(bmp.pixel(4, 4), Color::WHITE.to_u32());
+        // 透明な部分は背景のまま
+        assert_eq!(bmp.pixel(10, 2), original.pixel(10, 2));
+        // 重なる位置へ動かしても、端ではみ出しても、消せば元に戻る
+        for (x, y) in [(5, 6), (12, 10), (-3, -4), (15, 15), (i64::MIN, i64::MAX)] {
+            cursor.draw(&mut bmp, x, y).unwrap();
+            assert_eq!(cursor.position(), Some((x, y)));
+        }
+        cursor.draw(&mut bmp, 12, 10).unwrap();
+        assert!(bmp.buf != original.buf);
+        cursor.erase(&mut bmp).unwrap();
+        assert_eq!(cursor.position(), None);
+        assert!(bmp.buf == original.buf);
+        cursor.erase(&mut bmp).unwrap();
+        assert!(bmp.buf == original.buf);
+    }
+
+    #[test_case]
+    fn rect_geometry() {
+        let a = Rect::new(-4, -2, 10, 6);
+        assert!(a.contains(Point::new(-4, -2)));
+        assert!(a.contains(Point::new(5, 3)));
+        assert!(!a.contains(Point::new(6, 3)));
+        assert!(!a.contains(Point::new(5, 4)));
+        assert_eq!((a.right(), a.bottom()), (6, 4));
+        assert_eq!(
+            a.intersection(&Rect::new(2, 1, 10, 10)),
+            Some(Rect::new(2, 1, 4, 3))
+        );
+        assert_eq!(
+            a.intersection(&Rect::new(-10, -10, 8, 9)),
+            Some(Rect::new(-4, -2, 2, 1))
+        );
+        assert_eq!(a.intersection(&a), Some(a));
+        // 辺が接しているだけ、または離れている場合は共通部分がない
+        assert_eq!(a.intersection(&Rect::new(6, 0, 3, 3)), None);
+        assert_eq!(a.intersection(&Rect::new(0, -5, 3, 3)), None);
+        assert_eq!(a.intersection(&Rect::new(20, 20, 3, 3)), None);
+        assert_eq!(a.intersection(&Rect::new(0, 0, 0, 3)), None);
+        // clamp_toは常に相手の内側に収まる
+        let screen = Rect::new(0, 0, 16, 16);
+        assert_eq!(a.clamp_to(&screen), Rect::new(0, 0, 6, 4));
+        assert_eq!(
+            Rect::new(20, -5, 3, 3).clamp_to(&screen),
+            Rect::new(16, 0, 0, 0)
+        );
+        assert!(Rect::new(-8, 3, 4, 4).clamp_to(&screen).is_empty());
+        let huge = Rect::new(i64::MIN, i64::MIN, i64::MAX, i64::MAX);
+        assert_eq!(huge.right(), -1);
+        let all = Rect::new(0, 0, i64::MAX, i64::MAX);
+        assert_eq!(all.clamp_to(&screen), screen);
+        let left_of_screen = Rect::new(i64::MIN, 0, i64::MAX, 8);
+        assert_eq!(screen.clamp_to(&left_of_screen), Rect::new(-1, 0, 0, 8));
+    }
+
+    #[test_case]
+    fn rect_and_point_entry_points() {
+        let mut expected = TestBitmap::new();
+        fill_rect(&mut expected, 1, 2, 3, 4, 5).unwrap();
+        draw_line(&mut expected, 2, -3, 1, 20, 9).unwrap();
+        let mut actual = TestBitmap::new();
+        fill_rect_r(&mut actual, 1, Rect::new(2, 3, 4, 5)).unwrap();
+        draw_line_p(&mut actual, 2, Point::new(-3, 1), Point::new(20, 9)).unwrap();
+        assert!(expected.buf == actual.buf);
+        assert!(fill_rect_r(&mut actual, 1, Rect::new(14, 0, 3, 1)).is_err());
+    }
+
+    #[test_case]
+    fn clipped_skips_pixels_outside() {
+        let mut bmp = TestBitmap::new();
+        let clip = ClipRect {
+            x: 2,
+            y: 3,
+            w: 5,
+            h: 4,
+        };
+        let in_clip = |x, y| (2..7).contains(&x) && (3..7).contains(&y);
+        {
+            let mut c = Clipped::new(&mut bmp, clip);
+            fill_rect_clipped(&mut c, 1, 0, 0, 16, 16).unwrap();
+            assert!(fill_rect(&mut c, 1, 0, 0, 16, 16).is_err());
+            let _ = draw_line(&mut c, 2, 2, 3, 6, 6);
+            draw_str_fg(&mut c, 0, 0, 3, "AB");
+            let _ = fill_circle(&mut c, 4, 8, 8, 3);
+            let mut src = TestBitmap::new();
+            fill_rect(&mut src, 5, 0, 0, 16, 16).unwrap();
+            transfer_rect(&mut c, &src, 0, 0, 6, 0, 16, 16).unwrap();
+            // 入れ子にすると共通部分だけが残る
+            let mut inner = Clipped::new(&mut c, ClipRect { x: 5, y: 0, w: 100, h: 4 });
+            assert!(inner.pixel_at(4, 3).is_none());
+            fill_rect_clipped(&mut inner, 6, 0, 0, 16, 16).unwrap();
+        }
+        for y in 0..TEST_BITMAP_SIZE {
+            for x in 0..TEST_BITMAP_SIZE {
+                assert_eq!(bmp.pixel(x, y) != 0, in_clip(x, y));
+            }
+        }
+        assert_eq!(bmp.pixel(6, 4), 5);
+        assert_eq!(bmp.pixel(5, 3), 6);
+        assert_eq!(bmp.pixel(6, 3), 6);
+        assert_eq!(bmp.pixel(2, 3), 2);
+        assert_eq!(bmp.pixel(4, 3), 3);
+    }
+
+    #[test_case]
+    fn sprite_with_save_and_restore() {
+        let mut bmp = TestBitmap::new();
+        for (i, p) in bmp.buf.iter_mut().enumerate() {
+            *p = i as u32;
+        }
+        let background = bmp.buf;
+        let sprite = [K, 1, 1, K];
+        let mut saved = [0u32; 4];
+        for (x, y) in [(3, 4), (-1, -1), (15, 15), (i64::MAX, i64::MIN)] {
+            save_rect(&bmp, x, y, 2, 2, &mut saved).unwrap();
+            draw_sprite(&mut bmp, x, y, 2, 2, &sprite, Some(K)).unwrap();
+            if x == 3 {
+                assert_eq!(bmp.pixel(3, 4), background[4 * 16 + 3]);
+                assert_eq!(bmp.pixel(4, 4), 1);
+                assert_eq!(bmp.pixel(3, 5), 1);
+            }
+            draw_sprite(&mut bmp, x, y, 2, 2, &saved, None).unwrap();
+            assert!(bmp.buf == background);
+        }
+        assert!(draw_sprite(&mut bmp, 0, 0, 2, 2, &sprite[..3], None).is_err());
+        assert!(save_rect(&bmp, 0, 0, 2, 1, &mut saved).is_err());
+        assert!(save_rect(&bmp, 0, 0, 0, 0, &mut []).is_err());
+    }
+
+    #[test_case]
+    fn draw_test_pattern_fits_any_buffer() {
+        let mut bmp = CanaryBitmap::new();
+        draw_test_pattern(&mut bmp);
+        draw_test_pattern_at(&mut bmp, -5, -7, 3);
+        bmp.assert_canaries();
+        // 線に重ならない点で、色のセルが描かれていることを確かめる
+        let mut bmp = BitmapBuffer::new(64, 128);
+        draw_test_pattern_at(&mut bmp, 0, 0, 4);
+        assert_eq!(bmp.pixel_at(2, 5), Some(Color::RED.to_u32()));
+        assert_eq!(bmp.pixel_at(6, 4), Some(Color::RED.invert().to_u32()));
+        assert_eq!(bmp.pixel_at(2, 10), Some(Color::GREEN.to_u32()));
+        assert_eq!(bmp.pixel_at(0, 0), Some(0xffffff));
+    }
+
+    #[test_case]
+    fn test_pattern_height_is_exact() {
+        let mut bmp = BitmapBuffer::new(96, 128);
+        draw_test_pattern_at(&mut bmp, 0, 0, 4);
+        let h = test_pattern_height(4);
+        let row_is_empty = |y| (0..96).all(|x| bmp.pixel_at(x, y) == Some(0));
+        // 最後の行はグラデーションの帯で、その下には何も描かれない
+        assert!(!row_is_empty(h - 1));
+        for y in h..128 {
+            assert!(row_is_empty(y));
+        }
+    }
+
+    #[test_case]
+    fn fill_rect_reads_back_with_pixel_at() {
+        let mut bmp = BitmapBuffer::new(20, 10);
+        fill_rect(&mut bmp, 0x00c0ffee, 3, 2, 5, 4).unwrap();
+        for y in 0..10 {
+            for x in 0..20 {
+                let inside = (3..=7).contains(&x) && (2..=5).contains(&y);
+                let expected = if inside { 0x00c0ffee } else { 0 };
+                assert_eq!(bmp.pixel_at(x, y), Some(expected));
+            }
+        }
+        // 四隅と、そのすぐ外側
+        for (x, y) in [(3, 2), (7, 2), (3, 5), (7, 5)] {
+            assert_eq!(bmp.pixel_at(x, y), Some(0x00c0ffee));
+        }
+        for (x, y) in [(2, 2), (8, 5), (3, 1), (7, 6)] {
+            assert_eq!(bmp.pixel_at(x, y), Some(0));
+        }
+        for (x, y) in [(-1, 0), (20, 0), (0, -1), (0, 10)] {
+            assert_eq!(bmp.pixel_at(x, y), None);
+        }
+    }
+
+    #[test_case]
+    fn fill_rect_matches_per_pixel_at_any_alignment() {
+        // 先頭が8バイト境界にある場合とない場合、幅が奇数の場合と偶数の場合をすべて試す
+        for px in 0..CANARY_WIDTH {
+            for w in 1..=CANARY_WIDTH - px {
+                let mut expected = CanaryBitmap::new();
+                for y in 2..5 {
+                    for x in px..px + w {
+                        unsafe { unchecked_draw_point(&mut expected, 0x123456, x, y) };
+                    }
+                }
+                let mut actual = CanaryBitmap::new();
+                fill_rect(&mut actual, 0x123456, px, 2, w, 3).unwrap();
+                assert!(expected.buf == actual.buf);
+                let filled = actual.buf.iter().filter(|c| **c == 0x123456).count();
+                assert_eq!(filled as i64, w * 3);
+                actual.assert_canaries();
+                assert!(fill_rect(&mut actual, 0, px, 2, CANARY_WIDTH - px + 1, 3).is_err());
+                assert!(expected.buf == actual.buf);
+            }
+        }
+    }
+
+    #[test_case]
+    fn fill_rect_benchmark() {
+        let mut expected = BitmapBuffer::new(640, 480);
+        let begin = crate::x86::rdtsc();
+        for y in 0..480 {
+            for x in 0..640 {
+                unsafe { unchecked_draw_point(&mut expected, 0x123456, x, y) };
+            }
+        }
+        let per_pixel = crate::x86::rdtsc() - begin;
+        let mut actual = BitmapBuffer::new(640, 480);
+        let begin = crate::x86::rdtsc();
+        fill_rect(&mut actual, 0x123456, 0, 0, 640, 480).unwrap();
+        let fast = crate::x86::rdtsc() - begin;
+        crate::info!("fill_rect 640x480: {fast} cycles (per-pixel loop: {per_pixel} cycles)");
+        assert!(expected.buf == actual.buf);
+    }
+
+    #[test_case]
+    fn color_conversions() {
+        assert_eq!(u32::from(Color::rgb(0x12, 0x34, 0x56)), 0x123456);
+        assert_eq!(Color::from(0xff123456), Color::rgb(0x12, 0x34, 0x56));
+        assert_eq!(Color::from_hex(0x123456).to_u32(), 0x123456);
+        assert_eq!(Color::RED.invert(), Color::CYAN);
+        assert_eq!(Color::rgb(0x12, 0x34, 0x56).invert().to_u32(), 0xedcba9);
+        assert_eq!(Color::RED.to_native(PixelFormat::Bgrx), 0xff0000);
+        assert_eq!(Color::RED.to_native(PixelFormat::Rgbx), 0x0000ff);
+        assert_eq!(Color::lerp(Color::BLACK, Color::WHITE, 0), Color::BLACK);
+        assert_eq!(Color::lerp(Color::BLACK, Color::WHITE, 255), Color::WHITE);
+        let mut a = TestBitmap::new();
+        let mut b = TestBitmap::new();
+        fill_rect(&mut a, Color::CYAN, 0, 0, 4, 4).unwrap();
+        fill_rect(&mut b, 0x00ffff, 0, 0, 4, 4).unwrap();
+        assert!(a.buf == b.buf);
+    }
+
+    #[test_case]
+    fn sprite_sheet_draw_cell() {
+        let sheet = SpriteSheet::new(&SHEET, 4, 4, 2, 2).unwrap().with_color_key(K);
+        assert_eq!(sheet.cell_count(), 4);
+        let mut bmp = TestBitmap::new();
+        sheet.draw_cell(&mut bmp, 4, 4, 0, 0).unwrap();
+        assert_eq!(bmp.pixel(4, 4), 1);
+        assert_eq!(bmp.pixel(5, 4), 0);
+        assert_eq!(bmp.pixel(4, 5), 0);
+        assert_eq!(bmp.pixel(5, 5), 2);
+        sheet.draw_cell(&mut bmp, 8, 8, 1, 1).unwrap();
+        assert_eq!(bmp.pixel(8, 8), 0);
+        assert_eq!(bmp.pixel(9, 9), 7);
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 3);
+    }
+
+    #[test_case]
+    fn sprite_sheet_draw_cell_clipped() {
+        let sheet = SpriteSheet::new(&SHEET, 4, 4, 2, 2).unwrap().with_color_key(K);
+        let mut bmp = TestBitmap::new();
+        sheet.draw_cell(&mut bmp, 15, 15, 0, 1).unwrap();
+        sheet.draw_cell(&mut bmp, -1, -1, 0, 1).unwrap();
+        sheet.draw_cell(&mut bmp, i64::MIN, i64::MAX, 0, 1).unwrap();
+        assert_eq!(bmp.pixel(15, 15), 6);
+        assert_eq!(bmp.pixel(0, 0), 6);
+        assert_eq!(bmp.buf.iter().filter(|c| **c != 0).count(), 2);
+    }
+
+    #[test_case]
+    fn sprite_sheet_out_of_range_cell() {
+        let sheet = SpriteSheet::new(&SHEET, 4, 4, 2, 2).unwrap();
+        let mut bmp = TestBitmap::new();
+        assert!(sheet.draw_cell(&mut bmp, 0, 0, 2, 0).is_err());
+        assert!(sheet.draw_cell(&mut bmp, 0, 0, 0, -1).is_err());
+        assert!(SpriteSheet::new(&SHEET, 4, 5, 2, 2).is_err());
+    }
+
+    // 不透明・半透明・完全透明の画素を混ぜた、3x2のセルが2列x1行並んだシート
+    const ALPHA_SHEET: [u32; 12] = [
+        0xffff0000, 0x80ff0000, 0x00ff0000, 0xff00ff00, 0x4000ff00, 0x00000000, //
+        0xc00000ff, 0xff0000ff, 0x01ffffff, 0x00ffffff, 0xfe123456, 0xff000000, //
+    ];
+
+    #[test_case]
+    fn sprite_sheet_alpha() {
+        let sheet = SpriteSheet::new(&ALPHA_SHEET, 6, 2, 3, 2).unwrap().with_alpha();
+        let mut bmp = TestBitmap::new();
+        fill_rect(&mut bmp, 0x808080, 0, 0, 16, 16).unwrap();
+        sheet.draw_cell(&mut bmp, 0, 0, 0, 0).unwrap();
+        assert_eq!(bmp.pixel(0, 0), 0xff0000);
+        assert_eq!(bmp.pixel(1, 0), blend_pixel(0x808080, 0xff0000, 0x80));
+        assert_eq!(bmp.pixel(2, 0), 0x808080);
+        assert_eq!(bmp.pixel(0, 1), blend_pixel(0x808080, 0x0000ff, 0xc0));
+        assert_eq!(bmp.pixel(1, 1), 0x0000ff);
+        assert_eq!(bmp.pixel(2, 1), blend_pixel(0x808080, 0xffffff, 0x01));
+        // アルファの指定がなければ上位8ビットも含めてそのまま書き込む
+        let sheet = SpriteSheet::new(&ALPHA_SHEET, 6, 2, 3, 2).unwrap();
+        sheet.draw_cell(&mut bmp, 4, 4, 1, 0).unwrap();
+        assert_eq!(bmp.pixel(5, 4), 0x4000ff00);
+        assert_eq!(bmp.pixel(6, 4), 0);
+    }
+
+    #[test_case]
+    fn sprite_sheet_golden() {
+        let keyed = SpriteSheet::new(&SHEET, 4, 4, 2, 2).unwrap().with_color_key(K);
+        let alpha = SpriteSheet::new(&ALPHA_SHEET, 6, 2, 3, 2).unwrap().with_alpha();
+        // 各セルを並べたものと、右下の端で切り取られるもの
+        let mut bmp = BitmapBuffer::new(10, 8);
+        fill_rect(&mut bmp, 0x204060, 0, 0, 10, 8).unwrap();
+        for (i, (col, row)) in [(0, 0), (1, 0), (0, 1), (1, 1)].into_iter().enumerate() {
+            keyed.draw_cell(&mut bmp, i as i64 * 2, 0, col, row).unwrap();
         }
+        keyed.draw_cell(&mut bmp, 9, 7, 0, 0).unwrap();
+        assert_eq!(pixels_checksum(&bmp), 0x642f_6ccd_16d9_eba2);
+        let mut bmp = BitmapBuffer::new(10, 8);
+        fill_rect(&mut bmp, 0x204060, 0, 0, 10, 8).unwrap();
+        alpha.draw_cell(&mut bmp, 0, 0, 0, 0).unwrap();
+        alpha.draw_cell(&mut bmp, 3, 0, 1, 0).unwrap();
+        alpha.draw_cell(&mut bmp, 8, 7, 0, 0).unwrap();
+        alpha.draw_cell(&mut bmp, -1, 3, 1, 0).unwrap();
+        assert_eq!(pixels_checksum(&bmp), 0xf0f8_9009_347a_fd6c);
     }
-    draw_str_fg(buf, left, h * colors.len() as i64, 0x00ff00, "0123456789");
-    draw_str_fg(buf, left, h * colors.len() as i64 + 16, 0x00ff00, "ABCDEF");
 }
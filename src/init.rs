@@ -1,15 +1,122 @@
 use crate::allocator::ALLOCATOR;
+use crate::error;
+use crate::uefi::boot_services_exited;
+use crate::uefi::efi_con_out_print;
 use crate::uefi::exit_from_efi_boot_services;
 use crate::uefi::EfiHandle;
 use crate::uefi::EfiSystemTable;
 use crate::uefi::MemoryMapHolder;
+use crate::x86::beep;
+use crate::x86::wait_tsc_ticks;
+use core::fmt;
+
+/// Which phase of the boot sequence failed, and why.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootError {
+    Vram(&'static str),
+    MemoryMap(&'static str),
+    ExitBootServices(&'static str),
+    Allocator(&'static str),
+}
+impl BootError {
+    pub fn phase(&self) -> &'static str {
+        match self {
+            BootError::Vram(_) => "Vram",
+            BootError::MemoryMap(_) => "MemoryMap",
+            BootError::ExitBootServices(_) => "ExitBootServices",
+            BootError::Allocator(_) => "Allocator",
+        }
+    }
+    /// Number of beeps that report_boot_error() sounds for this phase.
+    pub fn beep_count(&self) -> usize {
+        match self {
+            BootError::Vram(_) => 1,
+            BootError::MemoryMap(_) => 2,
+            BootError::ExitBootServices(_) => 3,
+            BootError::Allocator(_) => 4,
+        }
+    }
+    pub fn cause(&self) -> &'static str {
+        match self {
+            BootError::Vram(e)
+            | BootError::MemoryMap(e)
+            | BootError::ExitBootServices(e)
+            | BootError::Allocator(e) => e,
+        }
+    }
+}
+impl fmt::Display for BootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Boot failed in phase {}: {}", self.phase(), self.cause())
+    }
+}
+
+#[test_case]
+fn boot_error_display_test() {
+    use core::fmt::Write;
+    struct Buf([u8; 64], usize);
+    impl Write for Buf {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.1 + s.len();
+            self.0.get_mut(self.1..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.1 = end;
+            Ok(())
+        }
+    }
+    let mut buf = Buf([0; 64], 0);
+    write!(buf, "{}", BootError::Vram("init_vram failed")).unwrap();
+    assert_eq!(&buf.0[..buf.1], b"Boot failed in phase Vram: init_vram failed");
+}
+
+const BEEP_HZ: u32 = 880;
+const BEEP_TSC_TICKS: u64 = 200_000_000;
+
+/// Reports a boot failure on every channel that may still work:
+/// the serial port and the PC speaker (beep_count() beeps) always,
+/// and the firmware console while boot services are alive.
+/// Returns Err if the firmware console could not be used.
+pub fn report_boot_error(
+    efi_system_table: &EfiSystemTable,
+    e: &BootError,
+) -> crate::result::Result<()> {
+    error!("{e}");
+    for _ in 0..e.beep_count() {
+        beep(BEEP_HZ, BEEP_TSC_TICKS);
+        wait_tsc_ticks(BEEP_TSC_TICKS);
+    }
+    if boot_services_exited() {
+        return Err("Boot services are not available after exit");
+    }
+    efi_con_out_print(efi_system_table, "Boot failed in phase ")?;
+    efi_con_out_print(efi_system_table, e.phase())?;
+    efi_con_out_print(efi_system_table, ": ")?;
+    efi_con_out_print(efi_system_table, e.cause())?;
+    efi_con_out_print(efi_system_table, "\n")
+}
+
+#[test_case]
+fn report_init_vram_failure_test() {
+    // テストはExitBootServices()の後に動くので、init_vram()は必ず失敗する
+    let efi_system_table = crate::test_runner::efi_system_table();
+    let e = match crate::uefi::init_vram(efi_system_table) {
+        Ok(_) => panic!("init_vram succeeded after ExitBootServices()"),
+        Err(e) => BootError::Vram(e),
+    };
+    assert_eq!(e.phase(), "Vram");
+    assert_eq!(e.beep_count(), 1);
+    // シリアルとビープでのみ報告され、ConOutには触らない
+    assert_eq!(
+        report_boot_error(efi_system_table, &e),
+        Err("Boot services are not available after exit")
+    );
+}
 
 pub fn init_basic_runtime (
     image_handle: EfiHandle,
     efi_system_table: &EfiSystemTable,
-) -> MemoryMapHolder {
+) -> Result<MemoryMapHolder, BootError> {
     let mut memory_map = MemoryMapHolder::new();
-    exit_from_efi_boot_services(image_handle, efi_system_table, &mut memory_map);
-    ALLOCATOR.init_with_mmap(&memory_map);
-    memory_map
+    exit_from_efi_boot_services(image_handle, efi_system_table, &mut memory_map)?;
+    ALLOCATOR.init_with_mmap(&memory_map).map_err(BootError::Allocator)?;
+    Ok(memory_map)
 }
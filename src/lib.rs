@@ -5,6 +5,9 @@
 #![reexport_test_harness_main = "run_united_tests"]
 #![no_main]
 pub mod allocator;
+pub mod bmp;
+pub mod checks;
+pub mod font;
 pub mod graphics;
 pub mod init;
 pub mod print;
@@ -20,6 +23,7 @@ pub mod test_runner;
 #[cfg(test)]
 #[no_mangle]
 fn efi_main(image_handle: uefi::EfiHandle, efi_system_table: &uefi::EfiSystemTable) {
-    init::init_basic_runtime(image_handle, efi_system_table);
+    test_runner::set_efi_system_table(efi_system_table);
+    init::init_basic_runtime(image_handle, efi_system_table).expect("init_basic_runtime failed");
     run_united_tests();
 }
@@ -3,13 +3,27 @@
 // no_stdだとmain()関数がstart(どの関数をはじめに実行するかを指定)の役割を果たしてる。
 #![feature(offset_of)]
 
+use core::cmp::min;
 use core::fmt::Write;
 use core::panic::PanicInfo;
 use core::writeln;
 use wasabi::graphics::draw_test_pattern;
 use wasabi::graphics::fill_rect;
+use wasabi::graphics::fill_rect_gradient;
+use wasabi::graphics::present;
+use wasabi::graphics::test_pattern_height;
+use wasabi::graphics::BackBuffer;
 use wasabi::graphics::Bitmap;
+use wasabi::graphics::BitmapRegion;
+use wasabi::graphics::Color;
+use wasabi::graphics::Cursor;
+use wasabi::graphics::GradientDirection;
+use wasabi::graphics::RotatedBitmap;
+use wasabi::graphics::Rotation;
+use wasabi::graphics::TEST_PATTERN_CELL;
 use wasabi::init::init_basic_runtime;
+use wasabi::init::report_boot_error;
+use wasabi::init::BootError;
 use wasabi::print::hexdump;
 use wasabi::println;
 use wasabi::error;
@@ -17,12 +31,59 @@ use wasabi::info;
 use wasabi::warn;
 use wasabi::qemu::exit_qemu;
 use wasabi::qemu::QemuExitCode;
+use wasabi::uefi::gop_blt_fill;
 use wasabi::uefi::init_vram;
 use wasabi::uefi::EfiHandle;
 use wasabi::uefi::EfiMemoryType;
 use wasabi::uefi::EfiSystemTable;
+use wasabi::uefi::MemoryMapHolder;
+use wasabi::uefi::VramBufferInfo;
 use wasabi::uefi::VramTextWriter;
 use wasabi::x86::hlt;
+use wasabi::x86::read_thermal_status;
+
+// パネルが回転して取り付けられている場合は、画面が正立して見えるようにここで指定する
+const PANEL_ROTATION: Rotation = Rotation::Rotate0;
+
+type Vram = RotatedBitmap<VramBufferInfo>;
+
+struct BootContext {
+    vram: Vram,
+    memory_map: MemoryMapHolder,
+}
+
+// 起動処理のどこで失敗したかをBootErrorとして返す
+fn try_boot(
+    image_handle: EfiHandle,
+    efi_system_table: &EfiSystemTable,
+) -> Result<BootContext, BootError> {
+    let mut vram = init_vram(efi_system_table).map_err(BootError::Vram)?;
+    let (pw, ph) = (vram.width(), vram.height());
+    gop_blt_fill(efi_system_table, 0x000000, 0, 0, pw, ph)
+        .or_else(|_| fill_rect(&mut vram, Color::BLACK, 0, 0, pw, ph))
+        .map_err(BootError::Vram)?;
+    // ここから先はすべて、回転を戻した(正立した)座標で描く
+    let mut vram = RotatedBitmap::new(vram, PANEL_ROTATION);
+    let vw = vram.width();
+    let vh = vram.height();
+    let memory_map = init_basic_runtime(image_handle, efi_system_table)?;
+    // ヒープが使えるようになったので、テストパターンは裏画面で描いてから一度に表示する
+    // 裏画面が確保できないほどメモリが少ない場合は、VRAMに直接描く
+    match BackBuffer::try_new_like(&vram) {
+        Ok(mut back) => {
+            let v = GradientDirection::Vertical;
+            fill_rect_gradient(&mut back, 0x000000, 0x000040, 0, 0, vw, vh, v)
+                .map_err(BootError::Vram)?;
+            draw_test_pattern(&mut back);
+            present(&back, &mut vram).map_err(BootError::Vram)?;
+        }
+        Err(e) => {
+            warn!("{e}, drawing directly to VRAM");
+            draw_test_pattern(&mut vram);
+        }
+    }
+    Ok(BootContext { vram, memory_map })
+}
 
 #[no_mangle]
 fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
@@ -33,13 +94,30 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     warn!("warn");
     error!("error");
     hexdump(efi_system_table);
-    let mut vram = init_vram(efi_system_table).expect("init_vram failed");
-    let vw = vram.width();
+    match read_thermal_status() {
+        Ok(t) => info!("{t:?}"),
+        Err(e) => info!("thermal status: {e}"),
+    }
+    let BootContext {
+        mut vram,
+        memory_map,
+    } = match try_boot(image_handle, efi_system_table) {
+        Ok(ctx) => ctx,
+        Err(e) => {
+            // ConOutが使えなくても、シリアルとビープで報告済み
+            let _ = report_boot_error(efi_system_table, &e);
+            loop {
+                hlt()
+            }
+        }
+    };
+    // テストパターンの下だけをコンソールにして、スクロールしてもパターンを消さない
     let vh = vram.height();
-    fill_rect(&mut vram, 0x000000, 0, 0, vw, vh).expect("fill_rect failed");
-    draw_test_pattern(&mut vram);
-    let mut w = VramTextWriter::new(&mut vram);
-    let memory_map = init_basic_runtime(image_handle, efi_system_table);
+    let vw = vram.width();
+    let console_top = min(test_pattern_height(TEST_PATTERN_CELL) + 16, vh / 2);
+    let mut console = BitmapRegion::new(&mut vram, 0, console_top, vw, vh - console_top)
+        .expect("VRAM is too small for the console");
+    let mut w = VramTextWriter::new(&mut console);
     let mut total_memory_pages = 0;
     for e in memory_map.iter() {
         if e.memory_type() != EfiMemoryType::CONVENTIONAL_MEMORY {
@@ -51,11 +129,25 @@ fn efi_main(image_handle: EfiHandle, efi_system_table: &EfiSystemTable) {
     let total_memory_size_mib = total_memory_pages * 4096 / 1024 / 1024;
     writeln!(w, "Total: {total_memory_pages} pages = {total_memory_size_mib} MiB").unwrap();
     writeln!(w, "Hello, Non-UEFI world!").unwrap();
+    // '\r'で同じ行を上書きする
+    for i in 0..=100 {
+        write!(w, "\rCounter: {i:3}").unwrap();
+    }
+    writeln!(w).unwrap();
+    move_cursor_demo(&mut vram);
     loop {
         hlt() // 空のloopだとCPUサイクルを消費してしまうので、HLT命令で割り込みが来るまで休ませる
     }
 }
 
+// マウスカーソルを動かしても背景が壊れないことを確かめる
+fn move_cursor_demo(vram: &mut Vram) {
+    let mut cursor = Cursor::new();
+    for i in 0..256 {
+        let _ = cursor.draw(vram, i * 3, i * 2);
+    }
+}
+
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     error!("PANIC: {info:?}");
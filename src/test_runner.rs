@@ -1,9 +1,26 @@
 use crate::qemu::exit_qemu;
 use crate::qemu::QemuExitCode;
 use crate::serial::SerialPort;
+use crate::uefi::EfiSystemTable;
 use core::any::type_name;
 use core::fmt::Write;
 use core::panic::PanicInfo;
+use core::ptr::null_mut;
+use core::sync::atomic::AtomicPtr;
+use core::sync::atomic::Ordering;
+
+// テストからもEfiSystemTableを使えるように、efi_main()で受け取ったものを覚えておく
+static EFI_SYSTEM_TABLE: AtomicPtr<EfiSystemTable> = AtomicPtr::new(null_mut());
+
+pub fn set_efi_system_table(efi_system_table: &EfiSystemTable) {
+    EFI_SYSTEM_TABLE.store(efi_system_table as *const _ as *mut _, Ordering::SeqCst);
+}
+pub fn efi_system_table() -> &'static EfiSystemTable {
+    let p = EFI_SYSTEM_TABLE.load(Ordering::SeqCst);
+    assert!(!p.is_null(), "efi_system_table is not set");
+    // SAFETY: the firmware keeps the system table alive until the machine resets.
+    unsafe { &*p }
+}
 
 pub trait Testable {
     fn run(&self, writer: &mut SerialPort);
@@ -1,10 +1,20 @@
-use crate::graphics::draw_font_fg;
+use crate::graphics::char_cells;
+use crate::graphics::draw_char;
+use crate::graphics::draw_font_scaled;
+use crate::graphics::fill_rect;
+use crate::graphics::fill_rect_clipped;
+use crate::graphics::scroll_up;
 use crate::graphics::Bitmap;
+use crate::graphics::Color;
+use crate::init::BootError;
 use crate::result::Result;
+use core::cmp::min;
 use core::fmt;
 use core::mem::offset_of;
 use core::mem::size_of;
 use core::ptr::null_mut;
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
 
 type EfiVoid = u8;
 pub type EfiHandle = u64;
@@ -158,16 +168,101 @@ const _: () = assert!(offset_of!(EfiBootServicesTable, locate_protocol) == 320);
 // efi_main()の第二引数に渡されるEfi System Tableからlocate_protocol()のアドレスを得る
 // EFI System Tableの中のEFI Boot Services Tableの中に書かれている
 
+#[repr(C)]
+struct EfiSimpleTextOutputProtocol {
+    _reserved0: u64,
+    output_string: extern "win64" fn(
+        this: *const EfiSimpleTextOutputProtocol,
+        string: *const u16,
+    ) -> EfiStatus,
+}
+
 #[repr(C)]
 pub struct EfiSystemTable {
-    _reserved0: [u64; 12],
-    pub boot_services: &'static EfiBootServicesTable,
+    _reserved0: [u64; 8],
+    con_out: *const EfiSimpleTextOutputProtocol,
+    _reserved1: [u64; 3],
+    boot_services: &'static EfiBootServicesTable,
 }
+const _: () = assert!(offset_of!(EfiSystemTable, con_out) == 64);
 const _: () = assert!(offset_of!(EfiSystemTable, boot_services) == 96);
 impl EfiSystemTable {
+    /// Boot Servicesへのアクセスは必ずここを通す。
+    /// ExitBootServices()の後に呼ばれた場合は、呼び出し元の位置と共にpanicする
+    #[track_caller]
     pub fn boot_services(&self) -> &EfiBootServicesTable {
-        self.boot_services
+        match self.try_boot_services() {
+            Ok(bs) => bs,
+            Err(e) => panic!("{e}"),
+        }
+    }
+    pub fn try_boot_services(&self) -> Result<&EfiBootServicesTable> {
+        if boot_services_exited() {
+            return Err("Boot services are not available after exit");
+        }
+        Ok(self.boot_services)
+    }
+}
+
+/// Prints `s` on the firmware's text console (ConOut). Only works before ExitBootServices().
+/// VRAMやシリアルが使えないときの最後の報告手段
+pub fn efi_con_out_print(efi_system_table: &EfiSystemTable, s: &str) -> Result<()> {
+    efi_system_table.try_boot_services()?;
+    let con_out = efi_system_table.con_out;
+    if con_out.is_null() {
+        return Err("ConOut is not available");
+    }
+    // UCS-2のNUL終端文字列に変換し、少しずつ出力する
+    let mut buf = [0u16; 64];
+    let mut len = 0;
+    let flush = |buf: &mut [u16; 64], len: &mut usize| -> Result<()> {
+        buf[*len] = 0;
+        *len = 0;
+        // SAFETY: con_out is non-null and boot services are still available.
+        let status = unsafe { ((*con_out).output_string)(con_out, buf.as_ptr()) };
+        if status != EfiStatus::Success {
+            return Err("ConOut output_string failed");
+        }
+        Ok(())
+    };
+    for c in s.chars() {
+        if c == '\n' {
+            buf[len] = '\r' as u16;
+            len += 1;
+        }
+        // BMP外の文字はUCS-2で表せないので'?'にする
+        buf[len] = u16::try_from(c as u32).unwrap_or('?' as u16);
+        len += 1;
+        if len >= buf.len() - 2 {
+            flush(&mut buf, &mut len)?;
+        }
     }
+    flush(&mut buf, &mut len)
+}
+
+// ExitBootServices()が成功した後はBoot ServicesもGOPのBlt()も使えなくなる
+static EXITED_BOOT_SERVICES: AtomicBool = AtomicBool::new(false);
+
+pub fn boot_services_exited() -> bool {
+    EXITED_BOOT_SERVICES.load(Ordering::SeqCst)
+}
+
+#[test_case]
+fn boot_services_exited_test() {
+    // テストはinit_basic_runtime()の後に実行される
+    assert!(boot_services_exited());
+}
+
+#[test_case]
+fn try_boot_services_after_exit_test() {
+    let efi_system_table = crate::test_runner::efi_system_table();
+    assert_eq!(
+        efi_system_table.try_boot_services().err(),
+        Some("Boot services are not available after exit")
+    );
+    // Boot Servicesを使う関数もpanicせずにErrを返す
+    assert!(efi_con_out_print(efi_system_table, "unreachable\n").is_err());
+    assert!(gop_blt_fill(efi_system_table, 0, 0, 0, 1, 1).is_err());
 }
 
 #[repr(C)]
@@ -192,17 +287,43 @@ struct EfiGraphicsOutputProtocolMode<'a> {
     pub frame_buffer_size: usize, // フレームバッファのバイト単位での大きさ
 }
 
+// Blt()に渡す操作の種類
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(dead_code)]
+enum EfiGraphicsOutputBltOperation {
+    VideoFill = 0,
+    VideoToBltBuffer,
+    BufferToVideo,
+    VideoToVideo,
+}
+
 #[repr(C)]
 #[derive(Debug)]
 struct EfiGraphicsOutPutProtocol<'a> {
-    reserved: [u64; 3],
+    reserved: [u64; 2],
+    // BltPixelはBlue, Green, Red, Reservedの順に並ぶので、u32として見ると0x00RRGGBBになる
+    blt: extern "win64" fn(
+        this: *const EfiGraphicsOutPutProtocol,
+        blt_buffer: *const u32,
+        blt_operation: EfiGraphicsOutputBltOperation,
+        source_x: usize,
+        source_y: usize,
+        destination_x: usize,
+        destination_y: usize,
+        width: usize,
+        height: usize,
+        delta: usize,
+    ) -> EfiStatus,
     pub mode: &'a EfiGraphicsOutputProtocolMode<'a>,
 }
+const _: () = assert!(offset_of!(EfiGraphicsOutPutProtocol, blt) == 16);
+const _: () = assert!(offset_of!(EfiGraphicsOutPutProtocol, mode) == 24);
 fn locate_graphic_protocol<'a>(
     efi_system_table: &EfiSystemTable,
 ) -> Result<&'a EfiGraphicsOutPutProtocol<'a>> {
     let mut graphic_output_protocol = null_mut::<EfiGraphicsOutPutProtocol>();
-    let status = (efi_system_table.boot_services.locate_protocol)(
+    let status = (efi_system_table.try_boot_services()?.locate_protocol)(
         &EFI_GRAPHICS_OUTPUT_PROTOCOL_GUID,
         null_mut::<EfiVoid>(),
         &mut graphic_output_protocol as *mut *mut EfiGraphicsOutPutProtocol as *mut *mut EfiVoid,
@@ -213,6 +334,76 @@ fn locate_graphic_protocol<'a>(
     Ok(unsafe { &*graphic_output_protocol })
 }
 
+
+fn blt_rect(x: i64, y: i64, w: i64, h: i64) -> Result<[usize; 4]> {
+    let to_usize = |v: i64| usize::try_from(v).or(Err("Out of Range"));
+    Ok([to_usize(x)?, to_usize(y)?, to_usize(w)?, to_usize(h)?])
+}
+
+/// Fills the rectangle with `color` using the firmware's GOP Blt() (EfiBltVideoFill).
+/// ファームウェアのBlt()で矩形を塗りつぶす。ExitBootServices()の前でのみ使える
+pub fn gop_blt_fill(
+    efi_system_table: &EfiSystemTable,
+    color: u32,
+    x: i64,
+    y: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    let gp = locate_graphic_protocol(efi_system_table)?;
+    let [x, y, w, h] = blt_rect(x, y, w, h)?;
+    let status = (gp.blt)(
+        gp,
+        &color,
+        EfiGraphicsOutputBltOperation::VideoFill,
+        0,
+        0,
+        x,
+        y,
+        w,
+        h,
+        0,
+    );
+    if status != EfiStatus::Success {
+        return Err("GOP Blt(VideoFill) failed");
+    }
+    Ok(())
+}
+
+/// Copies `w` x `h` pixels from `buf` (0x00RRGGBB, row-major) to (x, y) on the screen
+/// using the firmware's GOP Blt() (EfiBltBufferToVideo).
+/// ExitBootServices()の前にスプラッシュ画像などを一度に表示するために使う
+pub fn gop_blt_buffer_to_video(
+    efi_system_table: &EfiSystemTable,
+    buf: &[u32],
+    x: i64,
+    y: i64,
+    w: i64,
+    h: i64,
+) -> Result<()> {
+    let gp = locate_graphic_protocol(efi_system_table)?;
+    let [x, y, w, h] = blt_rect(x, y, w, h)?;
+    if w.checked_mul(h).ok_or("Out of Range")? > buf.len() {
+        return Err("Buffer too small");
+    }
+    let status = (gp.blt)(
+        gp,
+        buf.as_ptr(),
+        EfiGraphicsOutputBltOperation::BufferToVideo,
+        0,
+        0,
+        x,
+        y,
+        w,
+        h,
+        w * size_of::<u32>(),
+    );
+    if status != EfiStatus::Success {
+        return Err("GOP Blt(BufferToVideo) failed");
+    }
+    Ok(())
+}
+
 #[derive(Clone, Copy)]
 pub struct VramBufferInfo {
     buf: *mut u8,
@@ -233,6 +424,9 @@ impl Bitmap for VramBufferInfo {
     fn height(&self) -> i64 {
         self.height
     }
+    fn buf(&self) -> *const u8 {
+        self.buf
+    }
     fn buf_mut(&mut self) -> *mut u8 {
         self.buf
     }
@@ -248,50 +442,256 @@ pub fn init_vram(efi_system_table: &EfiSystemTable) -> Result<VramBufferInfo> {
     })
 }
 
-pub struct VramTextWriter<'a> {
-    vram: &'a mut VramBufferInfo,
+/// Text console on a bitmap: the VRAM itself, or e.g. a BitmapRegion of it to confine
+/// the console to a part of the screen.
+pub struct VramTextWriter<'a, T: Bitmap + ?Sized = VramBufferInfo> {
+    vram: &'a mut T,
     cursor_x: i64,
-    cursor_y: i64
+    cursor_y: i64,
+    scale: i64,
 }
-impl<'a> VramTextWriter<'a> {
-    pub fn new(vram: &'a mut VramBufferInfo) -> Self {
+impl<'a, T: Bitmap + ?Sized> VramTextWriter<'a, T> {
+    pub fn new(vram: &'a mut T) -> Self {
         Self {
             vram,
             cursor_x: 0,
             cursor_y: 0,
+            scale: 1,
+        }
+    }
+    /// A writer whose glyphs are scaled by `scale` (e.g. 2 for high-DPI displays).
+    /// Each cell becomes 8*scale x 16*scale pixels.
+    pub fn with_scale(vram: &'a mut T, scale: i64) -> Result<Self> {
+        if scale <= 0 {
+            return Err("VramTextWriter: scale must be positive");
+        }
+        let mut w = Self::new(vram);
+        w.scale = scale;
+        Ok(w)
+    }
+    fn cell_width(&self) -> i64 {
+        8 * self.scale
+    }
+    fn line_height(&self) -> i64 {
+        16 * self.scale
+    }
+    /// Current cursor position in character cells (column, row).
+    pub fn cursor(&self) -> (i64, i64) {
+        (self.cursor_x / self.cell_width(), self.cursor_y / self.line_height())
+    }
+    /// Moves the cursor to the given cell, e.g. to redraw a status line in place.
+    pub fn set_cursor(&mut self, col: i64, row: i64) -> Result<()> {
+        let cols = self.vram.width() / self.cell_width();
+        let rows = self.vram.height() / self.line_height();
+        if !(0..cols).contains(&col) || !(0..rows).contains(&row) {
+            return Err("Out of Range");
+        }
+        self.cursor_x = col * self.cell_width();
+        self.cursor_y = row * self.line_height();
+        Ok(())
+    }
+    fn new_line(&mut self) {
+        self.cursor_x = 0;
+        if self.cursor_y + 2 * self.line_height() <= self.vram.height() {
+            self.cursor_y += self.line_height();
+        } else {
+            // 次の行が画面に収まらないときは、画面全体を1行分上にずらす
+            self.scroll();
         }
     }
+    fn scroll(&mut self) {
+        let width = min(self.vram.width(), self.vram.pixels_per_line());
+        let height = self.vram.height();
+        let scrolled = scroll_up(self.vram, self.line_height(), Color::BLACK);
+        debug_assert!(scrolled.is_ok(), "scroll_up failed: {scrolled:?}");
+        // カーソル行から下を消す
+        let cleared =
+            fill_rect(self.vram, Color::BLACK, 0, self.cursor_y, width, height - self.cursor_y);
+        debug_assert!(cleared.is_ok(), "fill_rect failed: {cleared:?}");
+    }
 }
-impl fmt::Write for VramTextWriter<'_> {
+impl<T: Bitmap + ?Sized> fmt::Write for VramTextWriter<'_, T> {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         for c in s.chars() {
             if c == '\n' {
-                self.cursor_y += 16;
+                self.new_line();
+                continue;
+            }
+            if c == '\r' {
                 self.cursor_x = 0;
                 continue;
             }
-            draw_font_fg(self.vram, self.cursor_x, self.cursor_y, 0xffffff, c);
-            self.cursor_x += 8;
+            if c == '\t' {
+                // 次の8桁ごとのタブ位置へ進む。行に収まらなければ改行する
+                let tab = 8 * self.cell_width();
+                let next_x = (self.cursor_x / tab + 1) * tab;
+                if next_x >= self.vram.width() {
+                    self.new_line();
+                } else {
+                    self.cursor_x = next_x;
+                }
+                continue;
+            }
+            let advance = self.cell_width() * char_cells(c) as i64;
+            // 右端に収まらない文字は次の行に折り返す
+            if self.cursor_x > 0 && self.cursor_x + advance > self.vram.width() {
+                self.new_line();
+            }
+            if self.scale == 1 {
+                draw_char(self.vram, self.cursor_x, self.cursor_y, Color::WHITE, Color::BLACK, c);
+            } else {
+                // 画面からはみ出す部分は切り取られる
+                let _ = fill_rect_clipped(
+                    self.vram,
+                    Color::BLACK,
+                    self.cursor_x,
+                    self.cursor_y,
+                    advance,
+                    self.line_height(),
+                );
+                draw_font_scaled(self.vram, self.cursor_x, self.cursor_y, Color::WHITE, c, self.scale);
+            }
+            self.cursor_x += advance;
         }
         Ok(())
     }
 }
 
+#[cfg(test)]
+mod vram_text_writer_test {
+    use super::*;
+    use core::fmt::Write;
+
+    const W: i64 = 32;
+    const H: i64 = 48;
+
+    fn vram(buf: &mut [u32; (W * H) as usize]) -> VramBufferInfo {
+        VramBufferInfo {
+            buf: buf.as_mut_ptr() as *mut u8,
+            width: W,
+            height: H,
+            pixels_per_line: W,
+        }
+    }
+    fn expected_lines(lines: &[&str]) -> [u32; (W * H) as usize] {
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        for (i, line) in lines.iter().enumerate() {
+            crate::graphics::draw_str(&mut v, 0, i as i64 * 16, Color::WHITE, Color::BLACK, line);
+        }
+        buf
+    }
+
+    #[test_case]
+    fn scrolls_at_bottom() {
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        write!(VramTextWriter::new(&mut v), "a\nb\nc\nd").unwrap();
+        assert!(buf == expected_lines(&["b", "c", "d"]));
+    }
+
+    #[test_case]
+    fn new_line_on_last_line() {
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        write!(VramTextWriter::new(&mut v), "a\nb\nc\n").unwrap();
+        assert!(buf == expected_lines(&["b", "c", ""]));
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        write!(VramTextWriter::new(&mut v), "a\nb\nxx\ry").unwrap();
+        assert!(buf == expected_lines(&["a", "b", "yx"]));
+    }
+
+    #[test_case]
+    fn tab_and_cursor_positioning() {
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        let mut w = VramTextWriter::new(&mut v);
+        write!(w, "ab\tc").unwrap();
+        assert_eq!(w.cursor(), (1, 1));
+        w.set_cursor(2, 0).unwrap();
+        write!(w, "x").unwrap();
+        assert_eq!(w.cursor(), (3, 0));
+        assert!(w.set_cursor(4, 0).is_err());
+        assert!(w.set_cursor(0, 3).is_err());
+        assert!(buf == expected_lines(&["abx", "c"]));
+    }
+
+    #[test_case]
+    fn wraps_at_right_edge() {
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        write!(VramTextWriter::new(&mut v), "abcdefgh\nijklm").unwrap();
+        assert!(buf == expected_lines(&["efgh", "ijkl", "m"]));
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        write!(VramTextWriter::new(&mut v), "abcdefghij").unwrap();
+        assert!(buf == expected_lines(&["abcd", "efgh", "ij"]));
+    }
+
+    #[test_case]
+    fn writer_in_region() {
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        crate::graphics::fill_rect(&mut v, 0x123456, 0, 0, W, H).unwrap();
+        {
+            let mut region = crate::graphics::BitmapRegion::new(&mut v, 8, 16, 16, 32).unwrap();
+            write!(VramTextWriter::new(&mut region), "abc\nd").unwrap();
+        }
+        let mut expected = [0u32; (W * H) as usize];
+        let mut e = vram(&mut expected);
+        crate::graphics::fill_rect(&mut e, 0x123456, 0, 0, W, H).unwrap();
+        // スクロールで消えるのは最終行だけで、1行目の2文字目には元の色が残る
+        crate::graphics::fill_rect(&mut e, Color::BLACK, 8, 32, 16, 16).unwrap();
+        crate::graphics::draw_str(&mut e, 8, 16, Color::WHITE, Color::BLACK, "c");
+        crate::graphics::draw_str(&mut e, 8, 32, Color::WHITE, Color::BLACK, "d");
+        assert!(buf == expected);
+    }
+
+    #[test_case]
+    fn scaled_writer() {
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        assert!(VramTextWriter::with_scale(&mut v, 0).is_err());
+        let mut w = VramTextWriter::with_scale(&mut v, 2).unwrap();
+        write!(w, "ab").unwrap();
+        assert_eq!(w.cursor(), (2, 0));
+        let mut expected = [0u32; (W * H) as usize];
+        let mut e = vram(&mut expected);
+        crate::graphics::draw_str_fg_scaled(&mut e, 0, 0, Color::WHITE, "ab", 2);
+        assert!(buf == expected);
+        // 1行(32px)しか入らないので、折り返すと画面全体がスクロールして消える
+        let mut buf = [0u32; (W * H) as usize];
+        let mut v = vram(&mut buf);
+        write!(VramTextWriter::with_scale(&mut v, 2).unwrap(), "abc").unwrap();
+        let mut expected = [0u32; (W * H) as usize];
+        let mut e = vram(&mut expected);
+        crate::graphics::draw_str_fg_scaled(&mut e, 0, 0, Color::WHITE, "c", 2);
+        assert!(buf == expected);
+    }
+}
+
 // exit_boot_services()を呼び出すためのラッパー関数
 pub fn exit_from_efi_boot_services(
     image_handle: EfiHandle,
     efi_system_table: &EfiSystemTable,
     memory_map: &mut MemoryMapHolder,
-) {
-    loop {
-        let status = efi_system_table.boot_services.get_memory_map(memory_map);
-        assert_eq!(status, EfiStatus::Success);
-        let status = (efi_system_table.boot_services.exit_boot_services) (
+) -> core::result::Result<(), BootError> {
+    // メモリマップが取得後に変化するとmap_keyが古くなって失敗するので、何度か取り直す
+    const MAX_RETRIES: usize = 8;
+    for _ in 0..MAX_RETRIES {
+        let status = efi_system_table.boot_services().get_memory_map(memory_map);
+        if status != EfiStatus::Success {
+            return Err(BootError::MemoryMap("get_memory_map failed"));
+        }
+        let status = (efi_system_table.boot_services().exit_boot_services) (
             image_handle,
             memory_map.map_key,
         );
         if status == EfiStatus::Success {
-            break;
+            EXITED_BOOT_SERVICES.store(true, Ordering::SeqCst);
+            return Ok(());
         }
     }
+    Err(BootError::ExitBootServices("exit_boot_services kept failing"))
 }
@@ -1,4 +1,8 @@
+use crate::result::Result;
 use core::arch::asm;
+use core::arch::x86_64::CpuidResult;
+use core::arch::x86_64::__cpuid_count;
+use core::arch::x86_64::_rdtsc;
 
 pub fn hlt() {
     unsafe { asm!("hlt") }
@@ -18,4 +22,197 @@ pub fn read_io_port_u8(port: u16) -> u8 {
 
 pub fn write_io_port_u8(port: u16, data: u8) {
     unsafe { asm!("out dx, al", in("dx") port, in("al") data) }
-}
\ No newline at end of file
+}
+const PIT_FREQUENCY_HZ: u32 = 1_193_182;
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+const PC_SPEAKER: u16 = 0x61;
+
+/// Sounds the PC speaker at `hz` until roughly `tsc_ticks` TSC ticks have passed.
+/// 画面もシリアルも使えないときの報告手段なので、タイマ割り込みには頼らない
+pub fn beep(hz: u32, tsc_ticks: u64) {
+    let divisor = (PIT_FREQUENCY_HZ / hz.clamp(19, PIT_FREQUENCY_HZ)) as u16;
+    // チャンネル2を方形波モードにして、分周比を下位、上位の順に書く
+    write_io_port_u8(PIT_COMMAND, 0xb6);
+    write_io_port_u8(PIT_CHANNEL2, divisor as u8);
+    write_io_port_u8(PIT_CHANNEL2, (divisor >> 8) as u8);
+    let speaker = read_io_port_u8(PC_SPEAKER);
+    write_io_port_u8(PC_SPEAKER, speaker | 0b11);
+    wait_tsc_ticks(tsc_ticks);
+    write_io_port_u8(PC_SPEAKER, speaker & !0b11);
+}
+pub fn wait_tsc_ticks(tsc_ticks: u64) {
+    let start = rdtsc();
+    while rdtsc().wrapping_sub(start) < tsc_ticks {
+        busy_loop_hint();
+    }
+}
+/// Time stamp counter, for rough cycle counts in benchmarks.
+pub fn rdtsc() -> u64 {
+    // SAFETY: RDTSC is always available on x86_64.
+    unsafe { _rdtsc() }
+}
+pub fn cpuid(leaf: u32, subleaf: u32) -> CpuidResult {
+    // SAFETY: CPUID is always available on x86_64.
+    unsafe { __cpuid_count(leaf, subleaf) }
+}
+
+/// # Safety
+///
+/// The MSR must exist on this CPU, otherwise it raises #GP.
+pub unsafe fn read_msr(msr: u32) -> u64 {
+    let lo: u32;
+    let hi: u32;
+    asm!("rdmsr", in("ecx") msr, out("eax") lo, out("edx") hi);
+    (hi as u64) << 32 | lo as u64
+}
+
+fn is_genuine_intel() -> bool {
+    let r = cpuid(0, 0);
+    // "GenuineIntel"はEBX, EDX, ECXの順に格納されている
+    r.ebx == 0x756e6547 && r.edx == 0x49656e69 && r.ecx == 0x6c65746e
+}
+
+/// DisplayFamily and DisplayModel from CPUID.01H:EAX.
+fn family_model() -> (u32, u32) {
+    let eax = cpuid(1, 0).eax;
+    let family = (eax >> 8) & 0xf;
+    let model = (eax >> 4) & 0xf;
+    // 拡張ファミリ/モデルはファミリが0x6と0xfのときだけ加える
+    let display_family = if family == 0xf {
+        family + ((eax >> 20) & 0xff)
+    } else {
+        family
+    };
+    let display_model = if family == 0x6 || family == 0xf {
+        model | ((eax >> 16) & 0xf) << 4
+    } else {
+        model
+    };
+    (display_family, display_model)
+}
+
+// MSR_TEMPERATURE_TARGETはNehalem(06_1AH)以降のIntel CPUにしかない。
+// Atom(06_1CH)のように無いモデルもあるので、それ以外はTjMaxを読まない
+fn has_temperature_target() -> bool {
+    if !is_genuine_intel() {
+        return false;
+    }
+    let (family, model) = family_model();
+    family == 0x6 && model >= 0x1a && model != 0x1c
+}
+
+const CPUID_01_EDX_MCE: u32 = 1 << 7;
+const CPUID_01_EDX_MCA: u32 = 1 << 14;
+const CPUID_01_EDX_TM: u32 = 1 << 29;
+const CPUID_06_EAX_DTS: u32 = 1 << 0;
+const CPUID_06_EAX_PTM: u32 = 1 << 6;
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MC0_STATUS: u32 = 0x401;
+const IA32_THERM_STATUS: u32 = 0x19c;
+const MSR_TEMPERATURE_TARGET: u32 = 0x1a2;
+const IA32_PACKAGE_THERM_STATUS: u32 = 0x1b1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThermalStatus {
+    /// Degrees Celsius below TjMax (the throttling point). 0に近いほど熱い
+    pub readout_celsius_below_tjmax: u8,
+    /// Absolute temperature computed from TjMax, if TjMax could be read.
+    pub temperature_celsius: Option<u8>,
+    pub throttling_active: bool,
+    /// Throttling has happened at least once since the log bit was last cleared.
+    pub throttling_logged: bool,
+    pub package_throttling_active: Option<bool>,
+}
+
+/// Reads IA32_THERM_STATUS (and MSR_TEMPERATURE_TARGET on Intel CPUs that have it).
+/// QEMU(TCG)ではデジタル温度センサが無いのでErrが返る
+pub fn read_thermal_status() -> Result<ThermalStatus> {
+    if cpuid(0, 0).eax < 6 || cpuid(1, 0).edx & CPUID_01_EDX_TM == 0 {
+        return Err("Thermal monitor is not supported");
+    }
+    let pm = cpuid(6, 0).eax;
+    if pm & CPUID_06_EAX_DTS == 0 {
+        return Err("Digital thermal sensor is not supported");
+    }
+    // SAFETY: IA32_THERM_STATUS exists when CPUID.06H:EAX[0] is set.
+    let status = unsafe { read_msr(IA32_THERM_STATUS) };
+    if status & (1 << 31) == 0 {
+        return Err("Thermal readout is not valid");
+    }
+    let readout = ((status >> 16) & 0x7f) as u8;
+    let tjmax = if has_temperature_target() {
+        // SAFETY: has_temperature_target() only accepts models that implement it.
+        Some(((unsafe { read_msr(MSR_TEMPERATURE_TARGET) } >> 16) & 0xff) as u8)
+    } else {
+        None
+    };
+    let package_throttling_active = if pm & CPUID_06_EAX_PTM != 0 {
+        // SAFETY: IA32_PACKAGE_THERM_STATUS exists when CPUID.06H:EAX[6] is set.
+        Some(unsafe { read_msr(IA32_PACKAGE_THERM_STATUS) } & 1 != 0)
+    } else {
+        None
+    };
+    Ok(ThermalStatus {
+        readout_celsius_below_tjmax: readout,
+        temperature_celsius: tjmax.and_then(|t| t.checked_sub(readout)),
+        throttling_active: status & 1 != 0,
+        throttling_logged: status & 2 != 0,
+        package_throttling_active,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McBankStatus {
+    pub bank: usize,
+    pub raw: u64,
+}
+impl McBankStatus {
+    /// VAL: this bank holds a logged error.
+    pub fn is_valid(&self) -> bool {
+        self.raw & (1 << 63) != 0
+    }
+    pub fn is_uncorrected(&self) -> bool {
+        self.raw & (1 << 61) != 0
+    }
+    pub fn mca_error_code(&self) -> u16 {
+        self.raw as u16
+    }
+}
+
+/// Reads IA32_MCi_STATUS of every machine-check bank reported by IA32_MCG_CAP.
+pub fn read_mc_banks() -> Result<impl Iterator<Item = McBankStatus>> {
+    let edx = cpuid(1, 0).edx;
+    if edx & CPUID_01_EDX_MCE == 0 || edx & CPUID_01_EDX_MCA == 0 {
+        return Err("Machine check architecture is not supported");
+    }
+    // SAFETY: IA32_MCG_CAP exists when CPUID.01H:EDX.MCA is set.
+    let count = (unsafe { read_msr(IA32_MCG_CAP) } & 0xff) as usize;
+    Ok((0..count).map(|bank| McBankStatus {
+        bank,
+        // SAFETY: bank < IA32_MCG_CAP.Count
+        raw: unsafe { read_msr(IA32_MC0_STATUS + 4 * bank as u32) },
+    }))
+}
+
+#[test_case]
+fn read_thermal_status_test() {
+    // テストはQEMU(TCG)のqemu64で動かすので、CPUIDにTMビットが無い
+    assert_eq!(
+        read_thermal_status(),
+        Err("Thermal monitor is not supported")
+    );
+    // qemu64はファミリ0xfのCPUとして見えるので、TjMaxも読まない
+    assert_eq!(family_model().0, 0xf);
+    assert!(!has_temperature_target());
+}
+
+#[test_case]
+fn read_mc_banks_test() {
+    if let Ok(banks) = read_mc_banks() {
+        for (i, b) in banks.enumerate() {
+            assert_eq!(b.bank, i);
+        }
+    }
+}